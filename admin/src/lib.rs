@@ -13,14 +13,76 @@ wit_bindgen::generate!({
 enum AdminRequest {
     SetRouterProcess { process_id: String },
     SetRollupSequencer { address: String },
-    GetRollupState,
+    GetRollupState {
+        #[serde(default)]
+        force: bool,
+    },
+    /// `transaction` is a `SignedTransaction`; passed through to client as-is.
+    SubmitTransaction { transaction: serde_json::Value },
+    SetSigningKey { key: String },
+    /// `proposal` is a `Proposal`; passed through to client as-is.
+    CreateProposal { proposal: serde_json::Value },
+    Vote { proposal_hash: u64, is_yea: bool },
+    GetProposals,
+    SetChainRefreshInterval {
+        seconds: u64,
+        #[serde(default)]
+        jitter_percent: Option<u8>,
+    },
+    SaveWorkflow { name: String, json: String },
+    ListWorkflows,
+    DeleteWorkflow { name: String },
+    SetRetentionPolicy { max_jobs_retained: Option<u32>, max_age_hours: Option<u64>, dry_run: bool },
+    SetAllowedClients { nodes: Option<Vec<String>> },
+    /// One of "Debug", "Info", "Warn", or "Error".
+    SetLogLevel { level: String },
+    SetRollupSequencers { addresses: Vec<String> },
+    SetRouterHealthCheck { interval_seconds: u64, remove_unhealthy_after_seconds: Option<u64> },
+    GetRouterHealth,
+    SetImagesDrive { drive: String },
+    GetConfig,
+    ClearQueue,
+    ClearCurrentJob,
+    SetClientQuota { max_jobs_per_client: Option<u32> },
+    Shutdown,
+    ReplayJob { job_id: u64 },
+    SetParameters {
+        queue_response_timeout_seconds: u8,
+        serve_timeout_seconds: u16,
+        max_outstanding_payments: u8,
+        payment_period_hours: u8,
+    },
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 enum AdminResponse {
     SetRouterProcess { err: Option<String> },
     SetRollupSequencer { err: Option<String> },
-    GetRollupState { err: Option<String> },
+    GetRollupState { fetched_at: Option<u64>, err: Option<String> },
+    SubmitTransaction { tx_hash: Option<String>, err: Option<String> },
+    SetSigningKey { err: Option<String> },
+    CreateProposal { proposal_hash: Option<u64>, tx_hash: Option<String>, err: Option<String> },
+    Vote { err: Option<String> },
+    GetProposals(Vec<serde_json::Value>),
+    SetChainRefreshInterval { err: Option<String> },
+    SaveWorkflow { err: Option<String> },
+    ListWorkflows(Vec<String>),
+    DeleteWorkflow { err: Option<String> },
+    SetRetentionPolicy { err: Option<String> },
+    SetAllowedClients { err: Option<String> },
+    SetLogLevel { err: Option<String> },
+    SetRollupSequencers { err: Option<String> },
+    SetRouterHealthCheck { err: Option<String> },
+    GetRouterHealth(Vec<serde_json::Value>),
+    SetImagesDrive { err: Option<String> },
+    GetConfig(serde_json::Value),
+    ClearQueue { removed: usize },
+    ClearCurrentJob { removed: usize },
+    SetClientQuota { err: Option<String> },
+    Shutdown { err: Option<String> },
+    ReplayJob { queue_position: Option<usize>, job_id: Option<u64>, err: Option<String> },
+    SetParameters { err: Option<String> },
+    Error(String),
 }
 
 const PUBLISHER: &str = "nick1udwig.os";
@@ -1,46 +1,687 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
 
-use alloy_primitives::Address as AlloyAddress;
+use alloy_primitives::{hex, keccak256, Address as AlloyAddress, Signature};
+use alloy_signer::{LocalWallet, Signer, SignerSync};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use thiserror::Error;
 
-use kinode_process_lib::{timer, vfs};
+use kinode_process_lib::{http, timer, vfs};
 use kinode_process_lib::{
-    await_message, call_init, get_blob, get_typed_state, println, set_state,
-    Address, Message, LazyLoadBlob, ProcessId, Request, Response,
+    await_message, call_init, get_blob, get_state, set_state,
+    Address, Message, LazyLoadBlob, ProcessId, Request, Response, SendError, SendErrorKind,
 };
 
+// the world of `api/`, which kit copies into `wit` at build time
 wit_bindgen::generate!({
     path: "wit",
-    world: "process",
+    world: "comfyui-client-nick1udwig-dot-os-v0",
+    additional_derives: [serde::Deserialize, serde::Serialize],
 });
 
+const MAX_QUEUE_DEPTH: usize = 16;
+const MAX_JOB_HISTORY: usize = 256;
+const MAX_JOB_PRIORITY: u8 = 9;
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 128;
+const IDEMPOTENCY_KEY_TTL_SECONDS: u64 = 24 * 60 * 60;
+const MAX_WORKFLOW_NAME_LEN: usize = 128;
+const WORKFLOW_HASH_LEN: usize = 16;
+const STATE_BACKUP_FILE: &str = "state-backup.json";
+const DEFAULT_IMAGES_DRIVE: &str = "images";
+const WORKFLOWS_DRIVE: &str = "workflows";
+const STATE_DRIVE: &str = "state";
+const INPUTS_DRIVE: &str = "inputs";
+const MAX_INPUT_IMAGES: usize = 8;
+const MAX_SUBSCRIBERS_PER_JOB: usize = 16;
+const IMAGES_HTTP_PATH: &str = "/images/:job_id/:file";
+/// Images written before per-job directories were introduced live at the root
+/// of the images drive as `{job_id}-{n}.{ext}`.
+const LEGACY_IMAGES_HTTP_PATH: &str = "/images/:file";
+const JOB_HTTP_PATH: &str = "/job";
+const METRICS_HTTP_PATH: &str = "/metrics";
+/// A `JobParameters.workflow` of `@name` refers to a workflow saved with `AdminRequest::SaveWorkflow`.
+const WORKFLOW_REFERENCE_PREFIX: &str = "@";
+const UPDATES_WS_PATH: &str = "/updates";
+/// Bump, and add an arm to `State::migrate`, whenever `State` changes in a way
+/// that old states cannot deserialize into (i.e., a field without `#[serde(default)]`).
+const STATE_VERSION: u32 = 2;
+const DEFAULT_MAX_IMAGE_BYTES: u64 = 32 * 1024 * 1024;
+const THUMBNAIL_MAX_DIMENSION: u32 = 256;
+const THUMBNAIL_JPEG_QUALITY: u8 = 80;
+const DEFAULT_CHAIN_REFRESH_INTERVAL_SECONDS: u64 = 5 * 60;
+const DEFAULT_CHAIN_REFRESH_JITTER_PERCENT: u8 = 10;
+/// `AdminRequest::GetRollupState` serves chain state fetched this recently.
+const CHAIN_STATE_TTL_SECONDS: u64 = 30;
+const PROPOSAL_LIFETIME_SECONDS: u64 = 7 * 24 * 60 * 60;
+const DEFAULT_ROUTER_HEALTH_INTERVAL_SECONDS: u64 = 60;
+const ROUTER_PING_TIMEOUT_SECONDS: u64 = 10;
+const ROUTER_LATENCY_WINDOW: usize = 32;
+const IMAGE_GC_INTERVAL_SECONDS: u64 = 60 * 60;
+/// Used when `on_chain_state` timeouts are unset (0).
+const DEFAULT_QUEUE_RESPONSE_TIMEOUT_SECONDS: u64 = 20;
+const DEFAULT_SERVE_TIMEOUT_SECONDS: u64 = 60;
+const MAX_DISPATCH_RETRIES: u32 = 3;
+/// Retry `n` (1-indexed) waits `DISPATCH_RETRY_BASE_SECONDS * 2^(n - 1)`.
+const DISPATCH_RETRY_BASE_SECONDS: u64 = 2;
+/// Each attempt waits up to 5s for each sequencer's response; with backoff, a
+/// read from one sequencer gives up within about 16s.
+const SEQUENCER_READ_ATTEMPTS: u32 = 3;
+const SEQUENCER_RETRY_BASE_MS: u64 = 500;
+
+/// Messages below this level are not printed; mirrors `State.log_level`.
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+enum LogLevel {
+    Debug,
+    #[default]
+    Info,
+    Warn,
+    Error,
+}
+
+impl LogLevel {
+    fn set(self) {
+        LOG_LEVEL.store(self as u8, Ordering::Relaxed);
+    }
+
+    fn is_enabled(self) -> bool {
+        self as u8 >= LOG_LEVEL.load(Ordering::Relaxed)
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            LogLevel::Debug => "debug",
+            LogLevel::Info => "info",
+            LogLevel::Warn => "warn",
+            LogLevel::Error => "error",
+        }
+    }
+}
+
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => {
+        if $level.is_enabled() {
+            // the terminal is only reachable on a live node
+            #[cfg(not(test))]
+            kinode_process_lib::println!("{}: {}", $level.label(), format!($($arg)*));
+            #[cfg(test)]
+            std::eprintln!("{}: {}", $level.label(), format!($($arg)*));
+        }
+    };
+}
+macro_rules! log_debug { ($($arg:tt)*) => { log!(LogLevel::Debug, $($arg)*) }; }
+macro_rules! log_info { ($($arg:tt)*) => { log!(LogLevel::Info, $($arg)*) }; }
+macro_rules! log_warn { ($($arg:tt)*) => { log!(LogLevel::Warn, $($arg)*) }; }
+macro_rules! log_error { ($($arg:tt)*) => { log!(LogLevel::Error, $($arg)*) }; }
+
 #[derive(Debug, Serialize, Deserialize)]
 struct State {
+    /// Schema version; states saved before versioning are version 0.
+    #[serde(default)]
+    version: u32,
     current_job: Option<CurrentJob>,
+    job_queue: VecDeque<QueuedJob>,
+    /// Finished jobs, oldest first; bounded by `MAX_JOB_HISTORY`.
+    job_history: VecDeque<JobRecord>,
     router_process: Option<ProcessId>,
-    rollup_sequencer: Option<Address>,
+    /// Sequencers to read chain state from, tried in order.
+    rollup_sequencers: Vec<Address>,
+    /// The sequencer that last answered a read; writes go to it.
+    #[serde(default)]
+    active_sequencer: Option<Address>,
+    /// Hex-encoded secp256k1 private key of our DAO member account.
+    signing_key: Option<String>,
+    /// `PaymentRequired`s received in the current payment period.
+    outstanding_payments: u8,
+    /// Seconds since the UNIX epoch at which the current payment period began.
+    payment_period_started_at: u64,
+    /// Timers do not survive a restart, so neither does this.
+    #[serde(skip)]
+    payment_timer_armed: bool,
+    /// How often `on_chain_state` is re-fetched from `rollup_sequencers`; 0 disables.
+    chain_refresh_interval_seconds: u64,
+    /// Each refresh interval is moved by a random amount of up to this
+    /// percentage either way, so that clients started together spread out.
+    #[serde(default = "default_chain_refresh_jitter_percent")]
+    chain_refresh_jitter_percent: u8,
+    #[serde(skip)]
+    chain_refresh_timer_armed: bool,
+    /// VFS path of the state backup; set by `State::load`.
+    #[serde(skip)]
+    backup_path: Option<String>,
+    /// VFS path of the drive input images are staged in; set by `init`.
+    #[serde(skip)]
+    inputs_dir: Option<String>,
+    /// Open WebSocket channels on `UPDATES_WS_PATH`.
+    #[serde(skip)]
+    ws_channels: HashSet<u32>,
+    /// Source of `CurrentJob.timer_generation`s; never reused.
+    next_timer_generation: u64,
+    /// Incremented on every start and on `AdminRequest::Shutdown`, cancelling
+    /// the timers set before; see `TimerContext`.
+    #[serde(default)]
+    timer_epoch: u64,
+    /// `AdminRequest::Shutdown` has been handled; the message loop exits.
+    #[serde(skip)]
+    shutting_down: bool,
+    /// How the sequencer and routers are reached.
+    #[serde(skip, default = "default_transport")]
+    transport: Box<dyn Transport>,
+    /// Queued by the message being handled; see `run_effects`.
+    #[serde(skip)]
+    effects: Vec<Effect>,
+    /// Index into `on_chain_state.routers` of the next router to dispatch to.
+    router_cursor: usize,
+    /// Routers that failed their last dispatch; skipped by `select_router`.
+    unhealthy_routers: HashSet<String>,
+    /// Mixed into generated seeds so that jobs submitted together differ.
+    #[serde(default)]
+    seed_nonce: u64,
+    /// Finished jobs submitted with an `idempotency_key`, by `idempotency_scope`.
+    #[serde(default)]
+    idempotency_keys: HashMap<String, IdempotentJob>,
+    #[serde(default)]
+    retention_policy: RetentionPolicy,
+    /// Nodes other than ours that may `RunJob`; `None` allows any node.
+    #[serde(default)]
+    allowed_clients: Option<Vec<String>>,
+    /// Most jobs one node may have queued or in flight at once; `None` is unlimited.
+    #[serde(default)]
+    max_jobs_per_client: Option<u32>,
+    #[serde(default)]
+    local_parameters: LocalParameters,
+    /// Proposals dropped by `expire_proposals`.
+    #[serde(default)]
+    expired_proposals: HashSet<u64>,
+    /// Proposals applied by `apply_passed_proposals`.
+    #[serde(default)]
+    applied_proposals: HashSet<u64>,
+    #[serde(default)]
+    log_level: LogLevel,
+    #[serde(default)]
+    metrics: Metrics,
+    /// Seconds since the UNIX epoch of the last full fetch of `on_chain_state`.
+    #[serde(default)]
+    last_chain_fetch: Option<u64>,
+    /// How often every router is pinged; 0 disables. Routers that fail a ping
+    /// are added to `unhealthy_routers` until they answer one.
+    #[serde(default = "default_router_health_interval_seconds")]
+    router_health_interval_seconds: u64,
+    #[serde(skip)]
+    router_health_timer_armed: bool,
+    /// If set, propose removing routers unhealthy for this long.
+    #[serde(default)]
+    remove_unhealthy_routers_after_seconds: Option<u64>,
+    #[serde(default)]
+    router_health: HashMap<String, RouterHealth>,
+    /// Name of the VFS drive images are written to.
+    #[serde(default = "default_images_drive")]
+    images_drive: String,
+    #[serde(skip)]
+    image_gc_timer_armed: bool,
     on_chain_state: OnChainDaoState,
 }
 
+/// The job that has been dispatched to the router.
+/// `job_id` is `None` until the router either responds with
+/// `RunResponse::JobQueued` or sends its first `JobUpdate`; these may arrive in
+/// either order, and whichever arrives second must not reset progress made by
+/// the first.
 #[derive(Debug, Serialize, Deserialize)]
 struct CurrentJob {
-    job_id: u64,
+    job_id: Option<u64>,
+    parameters: JobParameters,
+    submitter: Address,
+    /// Node of the router the job is currently dispatched to.
+    router: String,
+    /// Routers the job has been dispatched to, including `router`.
+    routers_tried: Vec<String>,
     next_image_number: u32,
+    queued_at: u64,
+    /// Times the job has been re-sent to the router after a failed send.
+    retries: u32,
+    /// Seconds since the UNIX epoch of the next retry, if one is pending.
+    next_attempt_at: Option<u64>,
+    /// Generation of the most recently set timeout timer for this job;
+    /// only that timer may time the job out.
+    timer_generation: u64,
+    /// See `job_content_hash`.
+    #[serde(default)]
+    content_hash: String,
+    /// Images drive path the job's first image was written to; the rest follow it.
+    #[serde(default)]
+    images_dir: Option<String>,
+    /// Seconds since the UNIX epoch the job was sent to `router`.
+    #[serde(default)]
+    dispatched_at: Option<u64>,
+    /// The image being received in chunks, if any. Lost on restart, after
+    /// which the router must resend the image from its first chunk.
+    #[serde(skip)]
+    partial_image: Option<PartialImage>,
+    /// Processes sent a `JobEvent` as the job progresses; see
+    /// `PublicRequest::Subscribe`. Lost on restart.
+    #[serde(skip)]
+    subscribers: Vec<Address>,
+    /// The router whose address was last looked up on chain for this job,
+    /// so that a router not yet known is looked up once, not per image.
+    #[serde(skip)]
+    looked_up_router: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+struct PartialImage {
+    image_number: u32,
+    next_chunk_index: u32,
+    bytes_received: u64,
+    /// Of the chunks received so far.
+    hasher: Sha256,
+}
+
+/// Context of every timer the client sets. Timers cannot be cancelled, so
+/// those set in an earlier `State.timer_epoch` are ignored when they fire.
+#[derive(Debug, Serialize, Deserialize)]
+struct TimerContext {
+    kind: TimerKind,
+    epoch: u64,
+}
+
+/// All but `Job` re-arm when they fire, at the interval then configured: a
+/// timer already armed when its interval changes fires at the old interval,
+/// then re-arms at the new one.
+#[derive(Debug, Serialize, Deserialize)]
+enum TimerKind {
+    PaymentReset,
+    ChainRefresh,
+    RouterHealth,
+    ImageGc,
+    /// Times out, or retries, `current_job`.
+    Job(JobTimer),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct JobTimer {
+    job_id: Option<u64>,
+    phase: JobPhase,
+    generation: u64,
+}
+
+impl JobTimer {
+    /// Whether this is the latest timer set for `current_job`. Generations are
+    /// never reused, but the job id is compared too, so that even if they were,
+    /// e.g. a state restored from an old backup, a timer for an earlier job
+    /// whose router-assigned id has been recycled cannot time out a new one.
+    /// A timer set before the router assigned an id carries no id.
+    fn is_current(&self, current_job: &CurrentJob) -> bool {
+        let same_job = match self.job_id {
+            Some(job_id) => current_job.job_id == Some(job_id),
+            None => true,
+        };
+        same_job && self.generation == current_job.timer_generation
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum JobPhase {
+    /// Waiting on the router to respond with `RunResponse::JobQueued`.
+    Queue,
+    /// Waiting on the router to send the final `JobUpdate`.
+    Serve,
+    /// Waiting to re-send the job after a failed send to the router.
+    Retry,
+}
+
+/// A finished job. Timestamps are seconds since the UNIX epoch.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct JobRecord {
+    job_id: Option<u64>,
+    workflow: String,
+    image_count: u32,
+    outcome: JobOutcome,
+    queued_at: u64,
+    finished_at: u64,
+    /// Seed the job ran with.
+    #[serde(default)]
+    seed: Option<u64>,
+    /// What the job ran with, for `AdminRequest::ReplayJob`, with `workflow`
+    /// left empty: it is the record's own. Not in `PublicResponse::History`.
+    #[serde(default)]
+    job_parameters: Option<JobParameters>,
+}
+
+/// A `JobRecord` as reported by `PublicResponse::History`: the workflow is
+/// summarized rather than sent in full.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct JobSummary {
+    job_id: Option<u64>,
+    workflow: WorkflowSummary,
+    image_count: u32,
+    outcome: JobOutcome,
+    queued_at: u64,
+    finished_at: u64,
+    seed: Option<u64>,
+}
+
+impl From<&JobRecord> for JobSummary {
+    fn from(record: &JobRecord) -> Self {
+        let name = record.job_parameters.as_ref().and_then(|p| p.name.clone());
+        JobSummary {
+            job_id: record.job_id,
+            workflow: WorkflowSummary::new(name, &record.workflow),
+            image_count: record.image_count,
+            outcome: record.outcome.clone(),
+            queued_at: record.queued_at,
+            finished_at: record.finished_at,
+            seed: record.seed,
+        }
+    }
+}
+
+/// A running or queued job, as reported by `PublicResponse::ActiveJobs`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ActiveJob {
+    /// `None` until the router assigns one.
+    job_id: Option<u64>,
+    /// `0` for the running job, as in `RunResponse::QueuePosition`.
+    queue_position: usize,
+    /// Only reported to our own node.
+    submitter: Option<String>,
+    workflow: WorkflowSummary,
+    priority: u8,
+    images_received: u32,
+    queued_at: u64,
+}
+
+/// Identifies a workflow in query responses without its full JSON: its
+/// `JobParameters.name`, if any, and the first `WORKFLOW_HASH_LEN` hex digits
+/// of the sha256 of the workflow as sent to the router.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct WorkflowSummary {
+    name: Option<String>,
+    hash: String,
+}
+
+impl WorkflowSummary {
+    fn new(name: Option<String>, workflow: &str) -> Self {
+        let mut hash = hex::encode(Sha256::digest(workflow.as_bytes()));
+        hash.truncate(WORKFLOW_HASH_LEN);
+        WorkflowSummary { name, hash }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+enum JobOutcome {
+    Completed,
+    Failed(String),
+    TimedOut,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct IdempotentJob {
+    job_id: u64,
+    finished_at: u64,
+}
+
+/// Which job image directories are deleted every `IMAGE_GC_INTERVAL_SECONDS`.
+/// Unset limits do not apply; the current job's images are never deleted.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct RetentionPolicy {
+    /// Keep at most this many job directories, deleting the oldest first.
+    max_jobs_retained: Option<u32>,
+    /// Delete job directories whose job finished more than this long ago.
+    max_age_hours: Option<u64>,
+    /// Log what would be deleted instead of deleting it.
+    dry_run: bool,
+}
+
+impl RetentionPolicy {
+    fn is_unlimited(&self) -> bool {
+        self.max_jobs_retained.is_none() && self.max_age_hours.is_none()
+    }
+}
+
+/// Results of pinging a router. Timestamps are seconds since the UNIX epoch.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct RouterHealth {
+    /// When the router last answered a ping.
+    last_seen: Option<u64>,
+    /// When the router first failed a ping since last answering one.
+    unhealthy_since: Option<u64>,
+    /// We have proposed removing the router since it became unhealthy.
+    removal_proposed: bool,
+}
+
+/// Context of a `PublicRequest::Ping` health check sent to a router.
+#[derive(Debug, Serialize, Deserialize)]
+struct RouterPing {
+    router: String,
+}
+
+/// How the client is configured. Holds no secrets: of `State.signing_key`
+/// only the address it signs as is given.
+#[derive(Debug, Serialize, Deserialize)]
+struct ClientConfig {
+    router_process: Option<String>,
+    rollup_sequencers: Vec<String>,
+    active_sequencer: Option<String>,
+    signing_address: Option<String>,
+    chain_refresh_interval_seconds: u64,
+    chain_refresh_jitter_percent: u8,
+    router_health_interval_seconds: u64,
+    remove_unhealthy_routers_after_seconds: Option<u64>,
+    retention_policy: RetentionPolicy,
+    allowed_clients: Option<Vec<String>>,
+    max_jobs_per_client: Option<u32>,
+    log_level: LogLevel,
+    images_drive: String,
+    max_queue_depth: usize,
+    max_job_history: usize,
+    /// Timeouts and limits as last fetched from the chain.
+    dao_parameters: DaoParameters,
+    local_parameters: LocalParameters,
+    /// Parameters the chain leaves unset, for which `local_parameters` apply.
+    overridden_parameters: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RouterHealthSummary {
+    router: String,
+    healthy: bool,
+    last_seen: Option<u64>,
+    unhealthy_since: Option<u64>,
+}
+
+/// Job lifecycle counters since the state was created.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Metrics {
+    /// `RunJob`s received, whether or not they were accepted.
+    jobs_submitted: u64,
+    /// Jobs accepted into `job_queue`.
+    jobs_queued: u64,
+    jobs_completed: u64,
+    jobs_failed: u64,
+    jobs_timed_out: u64,
+    jobs_cancelled: u64,
+    /// Sum over completed jobs of seconds from submission to final image.
+    serve_latency_seconds_sum: u64,
+    /// Outcomes of finished jobs by the router node that last served them.
+    #[serde(default)]
+    routers: HashMap<String, RouterMetrics>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct RouterMetrics {
+    jobs_completed: u64,
+    jobs_failed: u64,
+    jobs_timed_out: u64,
+    jobs_cancelled: u64,
+    serve_latency_seconds_sum: u64,
+    /// Jobs failed over from this router to another.
+    #[serde(default)]
+    failovers: u64,
+    /// Seconds from dispatch to this router to final image of its last
+    /// `ROUTER_LATENCY_WINDOW` completed jobs, oldest first.
+    #[serde(default)]
+    recent_serve_latencies_seconds: VecDeque<u64>,
+}
+
+impl RouterMetrics {
+    fn record_serve_latency(&mut self, seconds: u64) {
+        self.recent_serve_latencies_seconds.push_back(seconds);
+        while self.recent_serve_latencies_seconds.len() > ROUTER_LATENCY_WINDOW {
+            self.recent_serve_latencies_seconds.pop_front();
+        }
+    }
+
+    /// Mean of `recent_serve_latencies_seconds`, if any.
+    fn recent_serve_latency_seconds(&self) -> Option<u64> {
+        let recent = &self.recent_serve_latencies_seconds;
+        if recent.is_empty() {
+            return None;
+        }
+        Some(recent.iter().sum::<u64>() / recent.len() as u64)
+    }
+}
+
+impl Metrics {
+    /// Render in the Prometheus text exposition format. Per-router series are
+    /// separate `comfyui_client_router_*` families so that summing them does
+    /// not double count the totals.
+    fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let mut counter = |name: &str, help: &str, values: Vec<(String, u64)>| {
+            out.push_str(&format!("# HELP comfyui_client_{name} {help}\n"));
+            out.push_str(&format!("# TYPE comfyui_client_{name} counter\n"));
+            for (labels, value) in values {
+                out.push_str(&format!("comfyui_client_{name}{labels} {value}\n"));
+            }
+        };
+        let mut routers: Vec<(&String, &RouterMetrics)> = self.routers.iter().collect();
+        routers.sort_by_key(|(router, _)| *router);
+        let by_router = |value: fn(&RouterMetrics) -> u64| -> Vec<(String, u64)> {
+            routers
+                .iter()
+                .map(|(router, metrics)| (format!("{{router=\"{router}\"}}"), value(metrics)))
+                .collect()
+        };
+        let total = |value: u64| vec![(String::new(), value)];
+        counter(
+            "jobs_submitted_total",
+            "RunJobs received, whether or not they were accepted.",
+            total(self.jobs_submitted),
+        );
+        counter("jobs_queued_total", "Jobs accepted into the queue.", total(self.jobs_queued));
+        counter(
+            "jobs_completed_total",
+            "Jobs that received their final image.",
+            total(self.jobs_completed),
+        );
+        counter("jobs_failed_total", "Jobs that failed.", total(self.jobs_failed));
+        counter(
+            "jobs_timed_out_total",
+            "Jobs that timed out while being served.",
+            total(self.jobs_timed_out),
+        );
+        counter("jobs_cancelled_total", "Jobs cancelled while running.", total(self.jobs_cancelled));
+        counter(
+            "serve_latency_seconds_sum",
+            "Sum over completed jobs of seconds from submission to final image.",
+            total(self.serve_latency_seconds_sum),
+        );
+        counter(
+            "router_jobs_completed_total",
+            "Jobs that received their final image, by router.",
+            by_router(|m| m.jobs_completed),
+        );
+        counter("router_jobs_failed_total", "Jobs that failed, by router.", by_router(|m| m.jobs_failed));
+        counter(
+            "router_jobs_timed_out_total",
+            "Jobs that timed out while being served, by router.",
+            by_router(|m| m.jobs_timed_out),
+        );
+        counter(
+            "router_jobs_cancelled_total",
+            "Jobs cancelled while running, by router.",
+            by_router(|m| m.jobs_cancelled),
+        );
+        counter(
+            "router_serve_latency_seconds_sum",
+            "Sum over completed jobs of seconds from submission to final image, by router.",
+            by_router(|m| m.serve_latency_seconds_sum),
+        );
+        counter(
+            "router_failovers_total",
+            "Jobs failed over to another router, by router.",
+            by_router(|m| m.failovers),
+        );
+        out.push_str(&format!(
+            "# HELP comfyui_client_router_recent_serve_latency_seconds Mean seconds from dispatch to final image \
+             of the last {ROUTER_LATENCY_WINDOW} completed jobs, by router.\n"
+        ));
+        out.push_str("# TYPE comfyui_client_router_recent_serve_latency_seconds gauge\n");
+        for (router, metrics) in routers.iter() {
+            if let Some(latency) = metrics.recent_serve_latency_seconds() {
+                out.push_str(&format!(
+                    "comfyui_client_router_recent_serve_latency_seconds{{router=\"{router}\"}} {latency}\n"
+                ));
+            }
+        }
+        out
+    }
+}
+
+/// A job waiting for `current_job` to finish before being dispatched.
+#[derive(Debug, Serialize, Deserialize)]
+struct QueuedJob {
+    parameters: JobParameters,
+    submitter: Address,
+    queued_at: u64,
+    /// See `job_content_hash`.
+    #[serde(default)]
+    content_hash: String,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 struct OnChainDaoState {
-    pub routers: Vec<String>,  // length 1 for now
+    pub routers: Vec<String>,
     pub members: HashMap<String, AlloyAddress>,
     pub proposals: HashMap<u64, ProposalInProgress>,
-    // pub client_blacklist: Vec<String>,
-    // pub member_blacklist: Vec<String>,
+    /// Client nodes the DAO has banned from running jobs.
+    #[serde(default)]
+    pub client_blacklist: Vec<String>,
+    /// Members whose votes are not counted and who may not propose or vote.
+    #[serde(default)]
+    pub member_blacklist: Vec<String>,
+    /// Stake of each member, as reported by the sequencer; only counted with
+    /// the `weighted-voting` feature.
+    #[serde(default)]
+    pub stakes: HashMap<String, u64>,
     pub queue_response_timeout_seconds: u8,
-    pub serve_timeout_seconds: u16, // TODO
+    pub serve_timeout_seconds: u16,
     pub max_outstanding_payments: u8,
     pub payment_period_hours: u8,
+    #[serde(default = "default_max_image_bytes")]
+    pub max_image_bytes: u64,
+}
+
+fn default_max_image_bytes() -> u64 {
+    DEFAULT_MAX_IMAGE_BYTES
+}
+
+fn default_images_drive() -> String {
+    DEFAULT_IMAGES_DRIVE.to_string()
+}
+
+fn default_chain_refresh_jitter_percent() -> u8 {
+    DEFAULT_CHAIN_REFRESH_JITTER_PERCENT
+}
+
+fn default_router_health_interval_seconds() -> u64 {
+    DEFAULT_ROUTER_HEALTH_INTERVAL_SECONDS
 }
 
 /// Possible proposals
@@ -48,9 +689,19 @@ struct OnChainDaoState {
 pub enum Proposal {
     ChangeRootNode(String),
     ChangeQueueResponseTimeoutSeconds(u8),
+    ChangeServeTimeoutSeconds(u16),
     ChangeMaxOutstandingPayments(u8),
     ChangePaymentPeriodHours(u8),
+    /// Largest image, in bytes, a router may send for a job.
+    ChangeMaxImageBytes(u64),
+    /// Address of the canonical sequencer, e.g. `rollup.os@sequencer:provider-dao-rollup:nick1udwig.os`.
+    ChangeSequencer(String),
     Kick(String),
+    AddRouter(String),
+    /// Ignored if it would leave the DAO with no routers.
+    RemoveRouter(String),
+    /// `address` is the hex-encoded address of the new member's signing key.
+    AddMember { node: String, address: String },
 }
 
 /// Possible proposals
@@ -58,6 +709,37 @@ pub enum Proposal {
 pub struct ProposalInProgress {
     pub proposal: Proposal,
     pub votes: HashMap<String, SignedVote>,
+    /// Seconds since the UNIX epoch; 0 if the sequencer did not say, in which
+    /// case it is set to when we first saw the proposal.
+    #[serde(default)]
+    pub created_at: u64,
+    /// Seconds since the UNIX epoch after which the proposal is dropped if it
+    /// has not passed; 0 if the sequencer did not say, in which case it is set
+    /// to `PROPOSAL_LIFETIME_SECONDS` after `created_at`.
+    #[serde(default)]
+    pub expires_at: u64,
+}
+
+/// Verified vote counts for a proposal
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TallyResult {
+    /// Sum of the weights of yea votes; see `vote_weight`.
+    pub yea: u64,
+    pub nay: u64,
+    /// More than half of the total member weight has cast a verified vote.
+    pub quorum_reached: bool,
+    /// More than half of the total member weight has voted yea.
+    pub passed: bool,
+}
+
+/// A proposal along with its current tally
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ProposalSummary {
+    pub proposal_hash: u64,
+    pub proposal: Proposal,
+    pub tally: TallyResult,
+    /// Seconds until the proposal expires.
+    pub expires_in_seconds: u64,
 }
 
 /// A vote on a proposal
@@ -71,15 +753,166 @@ pub struct Vote {
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct SignedVote {
     vote: Vote,
-    signature: u64,
+    /// 65-byte secp256k1 signature (r, s, v) over the EIP-191 message of the
+    /// JSON-serialized `vote`.
+    signature: Vec<u8>,
+}
+
+impl SignedVote {
+    /// Recover the signer of `vote` and check it is `member_addr`.
+    pub fn verify(&self, member_addr: &AlloyAddress) -> bool {
+        let Ok(payload) = serde_json::to_vec(&self.vote) else {
+            return false;
+        };
+        let Ok(signature) = Signature::try_from(self.signature.as_slice()) else {
+            return false;
+        };
+        match signature.recover_address_from_msg(&payload) {
+            Ok(signer) => &signer == member_addr,
+            Err(_) => false,
+        }
+    }
+}
+
+/// The weight of `member`'s vote: their stake with the `weighted-voting`
+/// feature (0 if they have none), else 1.
+#[cfg(feature = "weighted-voting")]
+fn vote_weight(stakes: &HashMap<String, u64>, member: &str) -> u64 {
+    stakes.get(member).copied().unwrap_or(0)
+}
+
+#[cfg(not(feature = "weighted-voting"))]
+fn vote_weight(_stakes: &HashMap<String, u64>, _member: &str) -> u64 {
+    1
+}
+
+/// Sum the weights of the verified votes on `proposal`.
+/// Blacklisted members neither vote nor count towards quorum.
+pub fn tally(
+    proposal: &ProposalInProgress,
+    members: &HashMap<String, AlloyAddress>,
+    member_blacklist: &[String],
+    stakes: &HashMap<String, u64>,
+) -> TallyResult {
+    let is_blacklisted = |member: &String| member_blacklist.contains(member);
+    let (mut yea, mut nay) = (0, 0);
+    for (member, signed_vote) in proposal.verified_votes(members) {
+        if is_blacklisted(member) {
+            continue;
+        }
+        if signed_vote.vote.is_yea {
+            yea += vote_weight(stakes, member);
+        } else {
+            nay += vote_weight(stakes, member);
+        }
+    }
+    let total_weight: u64 = members
+        .keys()
+        .filter(|member| !is_blacklisted(member))
+        .map(|member| vote_weight(stakes, member))
+        .sum();
+    TallyResult {
+        yea,
+        nay,
+        quorum_reached: (yea + nay) * 2 > total_weight,
+        passed: yea * 2 > total_weight,
+    }
+}
+
+impl ProposalInProgress {
+    /// Votes cast by current members whose signatures verify against the
+    /// member's on-chain address. Only these votes should be counted.
+    pub fn verified_votes<'a>(
+        &'a self,
+        members: &'a HashMap<String, AlloyAddress>,
+    ) -> impl Iterator<Item = (&'a String, &'a SignedVote)> {
+        self.votes.iter().filter(move |(member, signed_vote)| {
+            members
+                .get(*member)
+                .map(|member_addr| signed_vote.verify(member_addr))
+                .unwrap_or(false)
+        })
+    }
+
+    /// Record `member`'s vote. The vote must be for this proposal and signed by
+    /// the member's on-chain address. A member's first vote stands: re-casting
+    /// the same vote is a no-op and a changed vote is rejected.
+    pub fn record_vote(
+        &mut self,
+        member: &str,
+        signed_vote: SignedVote,
+        members: &HashMap<String, AlloyAddress>,
+    ) -> Result<(), String> {
+        if signed_vote.vote.proposal_hash != hash_proposal(&self.proposal) {
+            return Err(format!(
+                "vote by {member} is for proposal {}, not this one",
+                signed_vote.vote.proposal_hash,
+            ));
+        }
+        let Some(member_addr) = members.get(member) else {
+            return Err(format!("vote by {member}, who is not a member"));
+        };
+        if !signed_vote.verify(member_addr) {
+            return Err(format!("vote by {member} is not signed by {member_addr}"));
+        }
+        match self.votes.get(member) {
+            Some(existing) if existing.vote.is_yea != signed_vote.vote.is_yea => Err(format!(
+                "{member} already voted {}; votes cannot be changed",
+                if existing.vote.is_yea { "yea" } else { "nay" },
+            )),
+            Some(_) => Ok(()),
+            None => {
+                self.votes.insert(member.to_string(), signed_vote);
+                Ok(())
+            }
+        }
+    }
 }
 
 impl Default for State {
     fn default() -> Self {
         Self {
+            version: STATE_VERSION,
             current_job: None,
+            job_queue: VecDeque::new(),
+            job_history: VecDeque::new(),
             router_process: None,
-            rollup_sequencer: None,
+            rollup_sequencers: vec![],
+            active_sequencer: None,
+            signing_key: None,
+            outstanding_payments: 0,
+            payment_period_started_at: now(),
+            payment_timer_armed: false,
+            chain_refresh_interval_seconds: DEFAULT_CHAIN_REFRESH_INTERVAL_SECONDS,
+            chain_refresh_jitter_percent: DEFAULT_CHAIN_REFRESH_JITTER_PERCENT,
+            chain_refresh_timer_armed: false,
+            backup_path: None,
+            inputs_dir: None,
+            ws_channels: HashSet::new(),
+            next_timer_generation: 0,
+            timer_epoch: 0,
+            shutting_down: false,
+            transport: default_transport(),
+            effects: vec![],
+            router_cursor: 0,
+            unhealthy_routers: HashSet::new(),
+            seed_nonce: 0,
+            idempotency_keys: HashMap::new(),
+            retention_policy: RetentionPolicy::default(),
+            allowed_clients: None,
+            max_jobs_per_client: None,
+            local_parameters: LocalParameters::default(),
+            expired_proposals: HashSet::new(),
+            applied_proposals: HashSet::new(),
+            log_level: LogLevel::default(),
+            metrics: Metrics::default(),
+            last_chain_fetch: None,
+            router_health_interval_seconds: DEFAULT_ROUTER_HEALTH_INTERVAL_SECONDS,
+            router_health_timer_armed: false,
+            remove_unhealthy_routers_after_seconds: None,
+            router_health: HashMap::new(),
+            images_drive: default_images_drive(),
+            image_gc_timer_armed: false,
             on_chain_state: OnChainDaoState::default(),
         }
     }
@@ -92,51 +925,452 @@ impl Default for OnChainDaoState {
             routers: vec![],
             members: HashMap::new(),
             proposals: HashMap::new(),
+            client_blacklist: vec![],
+            member_blacklist: vec![],
+            stakes: HashMap::new(),
             queue_response_timeout_seconds: 0,
             serve_timeout_seconds: 0,
             max_outstanding_payments: 0,
             payment_period_hours: 0,
+            max_image_bytes: DEFAULT_MAX_IMAGE_BYTES,
         }
     }
 }
 
 impl State {
-    fn save(&self) -> anyhow::Result<()> {
-        set_state(&serde_json::to_vec(self)?);
+    fn config(&self) -> ClientConfig {
+        let signing_address = self
+            .signing_key
+            .as_ref()
+            .and_then(|key| key.parse::<LocalWallet>().ok())
+            .map(|wallet| wallet.address().to_string());
+        ClientConfig {
+            router_process: self.router_process.as_ref().map(|p| p.to_string()),
+            rollup_sequencers: self.rollup_sequencers.iter().map(|s| s.to_string()).collect(),
+            active_sequencer: self.active_sequencer.as_ref().map(|s| s.to_string()),
+            signing_address,
+            chain_refresh_interval_seconds: self.chain_refresh_interval_seconds,
+            chain_refresh_jitter_percent: self.chain_refresh_jitter_percent,
+            router_health_interval_seconds: self.router_health_interval_seconds,
+            remove_unhealthy_routers_after_seconds: self.remove_unhealthy_routers_after_seconds,
+            retention_policy: self.retention_policy.clone(),
+            allowed_clients: self.allowed_clients.clone(),
+            max_jobs_per_client: self.max_jobs_per_client,
+            log_level: self.log_level,
+            images_drive: self.images_drive.clone(),
+            max_queue_depth: MAX_QUEUE_DEPTH,
+            max_job_history: MAX_JOB_HISTORY,
+            dao_parameters: self.on_chain_state.parameters(),
+            local_parameters: self.local_parameters.clone(),
+            overridden_parameters: self.overridden_parameters(),
+        }
+    }
+
+    /// Names of the `local_parameters` in effect: those set locally that the
+    /// chain leaves unset (0).
+    fn overridden_parameters(&self) -> Vec<String> {
+        let dao = &self.on_chain_state;
+        let local = &self.local_parameters;
+        let mut overridden = vec![];
+        if dao.queue_response_timeout_seconds == 0 && local.queue_response_timeout_seconds != 0 {
+            overridden.push("queue_response_timeout_seconds".to_string());
+        }
+        if dao.serve_timeout_seconds == 0 && local.serve_timeout_seconds != 0 {
+            overridden.push("serve_timeout_seconds".to_string());
+        }
+        if dao.max_outstanding_payments == 0 && local.max_outstanding_payments != 0 {
+            overridden.push("max_outstanding_payments".to_string());
+        }
+        if dao.payment_period_hours == 0 && local.payment_period_hours != 0 {
+            overridden.push("payment_period_hours".to_string());
+        }
+        overridden
+    }
+
+    /// Queue persisting the state as it is once the message being handled has been.
+    fn save(&mut self) {
+        if !self.effects.iter().any(|effect| matches!(effect, Effect::SaveState)) {
+            self.effects.push(Effect::SaveState);
+        }
+    }
+
+    /// Write a backup to VFS before committing with `set_state`, so that if the
+    /// commit is interrupted, at least one intact copy remains. A failed backup
+    /// is logged and does not stop the commit.
+    fn persist(&self) -> anyhow::Result<()> {
+        let bytes = serde_json::to_vec(self)?;
+        if let Some(ref backup_path) = self.backup_path {
+            if let Err(e) = self.transport.write_file(backup_path, &bytes) {
+                log_error!("failed to back up state to {backup_path}: {e:#}");
+            }
+        }
+        self.transport.set_state(&bytes);
         Ok(())
     }
 
-    fn load() -> Self {
-        match get_typed_state(|bytes| Ok(serde_json::from_slice::<State>(bytes)?)) {
-            Some(rs) => rs,
+    /// Load from `set_state` through `transport`, falling back to the VFS
+    /// backup in `state_dir` if the primary state is missing or corrupt.
+    fn load(transport: Box<dyn Transport>, state_dir: &str) -> Self {
+        let backup_path = format!("{state_dir}/{STATE_BACKUP_FILE}");
+        let read_backup = || transport.read_file(&backup_path).ok();
+        let mut state = Self::restore(transport.state(), read_backup);
+        state.backup_path = Some(backup_path);
+        state.transport = transport;
+        state
+    }
+
+    /// The state saved as `primary`, else as the backup `read_backup` reads,
+    /// else a new one.
+    fn restore(primary: Option<Vec<u8>>, read_backup: impl FnOnce() -> Option<Vec<u8>>) -> Self {
+        if let Some(state) = primary.and_then(|bytes| Self::from_bytes(&bytes)) {
+            return state;
+        }
+        match read_backup().and_then(|bytes| Self::from_bytes(&bytes)) {
+            Some(state) => {
+                log_warn!("primary state missing or corrupt; loaded backup");
+                state
+            }
             None => State::default(),
         }
     }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let value: serde_json::Value = serde_json::from_slice(bytes).ok()?;
+        let version = value.get("version").and_then(|v| v.as_u64()).unwrap_or(0) as u32;
+        if version == STATE_VERSION {
+            return serde_json::from_value(value).ok();
+        }
+        match Self::migrate(value) {
+            Ok(state) => {
+                log_info!("migrated state from version {version} to {STATE_VERSION}");
+                Some(state)
+            }
+            Err(e) => {
+                log_error!("failed to migrate state from version {version}: {e:?}");
+                None
+            }
+        }
+    }
+
+    fn migrate(old: serde_json::Value) -> anyhow::Result<Self> {
+        let version = old.get("version").and_then(|v| v.as_u64()).unwrap_or(0);
+        match version {
+            0 => {
+                // version 0 predates the job queue; its in-flight job cannot be
+                //  resumed without its parameters, so only configuration is kept
+                let mut state = State::default();
+                if let Some(router_process) = old.get("router_process") {
+                    state.router_process = serde_json::from_value(router_process.clone())?;
+                }
+                if let Some(rollup_sequencer) = old.get("rollup_sequencer") {
+                    let rollup_sequencer: Option<Address> =
+                        serde_json::from_value(rollup_sequencer.clone())?;
+                    state.rollup_sequencers = rollup_sequencer.into_iter().collect();
+                }
+                if let Some(on_chain_state) = old.get("on_chain_state") {
+                    match serde_json::from_value(on_chain_state.clone()) {
+                        Ok(on_chain_state) => state.on_chain_state = on_chain_state,
+                        Err(e) => log_warn!("dropping unreadable on_chain_state ({e}); it will be re-fetched"),
+                    }
+                }
+                Ok(state)
+            }
+            1 => {
+                // version 1 had a single `rollup_sequencer`
+                let mut old = old;
+                let Some(fields) = old.as_object_mut() else {
+                    return Err(anyhow::anyhow!("state is not a JSON object"));
+                };
+                let rollup_sequencer: Option<Address> = match fields.remove("rollup_sequencer") {
+                    Some(rollup_sequencer) => serde_json::from_value(rollup_sequencer)?,
+                    None => None,
+                };
+                let rollup_sequencers: Vec<Address> = rollup_sequencer.into_iter().collect();
+                fields.insert("rollup_sequencers".to_string(), serde_json::to_value(rollup_sequencers)?);
+                fields.insert("version".to_string(), serde_json::json!(STATE_VERSION));
+                Ok(serde_json::from_value(old)?)
+            }
+            _ => Err(anyhow::anyhow!("no migration from state version {version}")),
+        }
+    }
 }
 
+/// A message no handler is for. Logged at debug level rather than as an error:
+/// unmatched traffic, e.g. probes, is expected.
 #[derive(Error, Debug)]
 enum NotAMatchError {
     #[error("Match failed")]
-    NotAMatch
+    NotAMatch,
+    #[error("unexpected request from {sender}: {body}")]
+    UnexpectedRequest { sender: String, body: String },
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Why a `PublicRequest::JobUpdate` was rejected.
+#[derive(Error, Debug)]
+enum JobError {
+    #[error("got JobUpdate for {job_id} with no current_job")]
+    NoCurrentJob { job_id: u64 },
+    #[error("rejecting JobUpdate for {job_id} from {sender}: job is served by {served_by:?}")]
+    WrongRouter { job_id: u64, sender: String, served_by: Option<String> },
+    #[error("got JobUpdate for {job_id} but current_job is {current_job_id}")]
+    UnknownJob { job_id: u64, current_job_id: u64 },
+    #[error("got JobUpdate for {job_id} with no blob")]
+    MissingBlob { job_id: u64 },
+    #[error("JobUpdate for {job_id} blob is {size} bytes, over the {limit} byte limit; dropping image")]
+    TooLarge { job_id: u64, size: u64, limit: u64 },
+    #[error("JobUpdate for {job_id} blob hashes to {actual}, expected {expected}")]
+    ChecksumMismatch { job_id: u64, actual: String, expected: String },
+    #[error("cannot verify JobUpdate for {job_id}: router {router} has no known address")]
+    UnknownRouter { job_id: u64, router: String },
+    #[error("JobUpdate for {job_id} signature does not match router {router}")]
+    BadSignature { job_id: u64, router: String },
+    #[error("got JobUpdate for {job_id} whose blob is not a {expected:?} image")]
+    WrongFormat { job_id: u64, expected: ImageFormat },
+    #[error(
+        "got chunk {chunk_index} of image {image_number} of job {job_id}, expected chunk {expected_chunk_index} of image {expected_image_number}"
+    )]
+    ChunkOutOfOrder {
+        job_id: u64,
+        image_number: u32,
+        chunk_index: u32,
+        expected_image_number: u32,
+        expected_chunk_index: u32,
+    },
+    #[error("failed to write image {path} of job {job_id}: {reason}")]
+    WriteFailed { job_id: u64, path: String, reason: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 enum PublicRequest {
     RunJob(JobParameters),
+    /// Check a job as `RunJob` would, without running it.
+    ValidateJob(JobParameters),
     /// Parameters in LazyLoadBlob.
-    JobUpdate { job_id: u64, is_final: bool, signature: Result<u64, String> },
+    /// `signature` is the router's signature over a `JobUpdateSignedPayload`,
+    /// or the reason the router failed to produce an image.
+    /// `sha256`, if given, is the hex-encoded digest of the blob.
+    JobUpdate {
+        job_id: u64,
+        is_final: bool,
+        signature: Result<Vec<u8>, String>,
+        #[serde(default)]
+        sha256: Option<String>,
+        /// Set if the blob is one chunk of an image too large to send at once;
+        /// `signature` and `sha256` are then those of the whole image.
+        #[serde(default)]
+        chunk: Option<ImageChunk>,
+    },
+    GetJobStatus { job_id: u64 },
+    CancelJob { job_id: u64 },
+    /// Most recent first.
+    GetHistory { limit: usize },
+    GetMetrics,
+    /// The running job, if any, then the queued jobs in dispatch order.
+    ListActiveJobs,
+    /// Liveness probe; answered by anyone, regardless of client allow- or blacklists.
+    Ping,
+    /// Push a `PublicResponse::JobEvent` to the sender each time the running
+    /// job `job_id` saves an image, and once more when it finishes. Answered by
+    /// `Subscribed`, or, for a job that has already finished, by its
+    /// `JobEvent::Finished`.
+    Subscribe { job_id: u64 },
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 enum PublicResponse {
     RunJob(RunResponse),
     JobUpdate,
+    /// `queued_at` is seconds since the UNIX epoch.
+    JobStatus {
+        job_id: u64,
+        images_received: u32,
+        is_complete: bool,
+        queued_at: u64,
+        seed: Option<u64>,
+        workflow: WorkflowSummary,
+    },
+    UnknownJob { job_id: u64 },
+    JobCancelled { job_id: u64, was_running: bool },
+    History(Vec<JobSummary>),
+    ActiveJobs(Vec<ActiveJob>),
+    Metrics(Metrics),
+    Pong {
+        /// Jobs waiting, not counting the one in flight.
+        queue_depth: usize,
+        /// `router_process` is set and the DAO has a router.
+        has_router: bool,
+        has_sequencer: bool,
+        /// Version of this package.
+        version: String,
+    },
+    Error(String),
+    /// Image bytes in LazyLoadBlob.
+    FinalImage { job_id: u64, output_format: ImageFormat },
+    Validation { ok: bool, errors: Vec<String> },
+    Subscribed { job_id: u64 },
+    /// Sent to subscribers as a new request; see `PublicRequest::Subscribe`.
+    JobEvent { job_id: u64, event: JobEvent },
+}
+
+impl PublicResponse {
+    /// Tells a submitter its job failed, or was refused, because of `reason`.
+    fn run_job_error(reason: impl Into<String>) -> Self {
+        PublicResponse::RunJob(RunResponse::Error(reason.into()))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum JobEvent {
+    ImageSaved { image_number: u32, is_final: bool },
+    /// The last event of a job.
+    Finished { outcome: JobOutcome, images_received: u32 },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct JobParameters {
     pub workflow: String,
     pub parameters: String,
+    #[serde(default)]
+    pub output_format: ImageFormat,
+    /// Also send the final image back to the submitter in the `LazyLoadBlob` of
+    /// a `PublicResponse::FinalImage`, for integrators without VFS access.
+    /// The whole image is held in memory by both processes while in transit,
+    /// so prefer reading from VFS for very large images.
+    #[serde(default)]
+    pub return_final_image: bool,
+    /// Sampler seed; filled in at submission when omitted so that the run can
+    /// be repeated. For a workflow graph, it is substituted into every node
+    /// with a `seed` or `noise_seed` input, before `parameters` are applied.
+    #[serde(default)]
+    pub seed: Option<u64>,
+    /// Number of non-final images the router is expected to send before the
+    /// final one. For a workflow graph, it is substituted into every node with
+    /// a `batch_size` input, before `parameters` are applied.
+    #[serde(default)]
+    pub batch_size: Option<u32>,
+    /// Also write a JPEG thumbnail, at most `THUMBNAIL_MAX_DIMENSION` on a side,
+    /// next to each image as `{n}-thumb.jpg`.
+    #[serde(default)]
+    pub thumbnail: bool,
+    /// Higher priority jobs are dispatched first; at most `MAX_JOB_PRIORITY`.
+    #[serde(default)]
+    pub priority: u8,
+    /// Resubmitting a job with the same key, from the same node, returns the
+    /// original job instead of enqueueing another, for up to
+    /// `IDEMPOTENCY_KEY_TTL_SECONDS` after it finishes.
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+    /// Reported, with a hash of `workflow`, in place of the workflow itself by
+    /// `GetJobStatus` and `GetHistory`. Defaults to the library name of an
+    /// `@name` workflow.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// For a `Jpg` job, re-encode each image at this quality, from 1 to 100,
+    /// before saving it, trading fidelity for size. The saved image then no
+    /// longer matches the router's signature over it.
+    #[serde(default)]
+    pub jpeg_quality: Option<u8>,
+    /// Images the workflow takes as input, e.g. for img2img. Their bytes are
+    /// sent, concatenated in this order, in the LazyLoadBlob of the `RunJob`,
+    /// both to the client and from it to the router.
+    #[serde(default)]
+    pub input_images: Vec<InputImage>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct InputImage {
+    /// Name the workflow refers to the image by, e.g. in a `LoadImage` node.
+    pub name: String,
+    /// Length in bytes; each image is at most the DAO's `max_image_bytes`.
+    pub size: u64,
+    /// Hex-encoded sha256, filled in when the image is staged. Staged images
+    /// are kept in the inputs drive under this name until the job finishes.
+    #[serde(default)]
+    pub sha256: Option<String>,
+}
+
+/// Format of the images the router sends back.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+enum ImageFormat {
+    /// ComfyUI's native output; retains embedded workflow metadata.
+    #[default]
+    Png,
+    Jpg,
+    Webp,
+}
+
+impl ImageFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Jpg => "jpg",
+            ImageFormat::Webp => "webp",
+        }
+    }
+
+    /// Check `bytes` begins with this format's magic number.
+    fn matches(&self, bytes: &[u8]) -> bool {
+        match self {
+            ImageFormat::Png => bytes.starts_with(&[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A]),
+            ImageFormat::Jpg => bytes.starts_with(&[0xFF, 0xD8, 0xFF]),
+            ImageFormat::Webp => {
+                bytes.len() >= 12 && &bytes[0..4] == b"RIFF" && &bytes[8..12] == b"WEBP"
+            }
+        }
+    }
+}
+
+/// The chunks of an image are sent in order, each as the blob of a
+/// `JobUpdate`, and appended to its file until `is_last_chunk`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ImageChunk {
+    image_number: u32,
+    chunk_index: u32,
+    is_last_chunk: bool,
+}
+
+/// What a router signs (EIP-191, JSON-serialized) for each `JobUpdate`.
+#[derive(Debug, Serialize, Deserialize)]
+struct JobUpdateSignedPayload {
+    job_id: u64,
+    /// Hex-encoded sha256 of the image bytes.
+    image_sha256: String,
+    is_final: bool,
+}
+
+impl JobUpdateSignedPayload {
+    /// Check `signature` over this payload recovers to `router_address`.
+    fn verify(&self, signature: &[u8], router_address: &AlloyAddress) -> bool {
+        let Ok(payload) = serde_json::to_vec(self) else {
+            return false;
+        };
+        let Ok(signature) = Signature::try_from(signature) else {
+            return false;
+        };
+        match signature.recover_address_from_msg(&payload) {
+            Ok(signer) => &signer == router_address,
+            Err(_) => false,
+        }
+    }
+}
+
+/// Pushed to WebSocket subscribers each time an image is written.
+#[derive(Debug, Serialize, Deserialize)]
+struct ImageEvent {
+    job_id: u64,
+    image_number: u32,
+    is_final: bool,
+}
+
+/// Written as a JSON sidecar next to each saved image.
+#[derive(Debug, Serialize, Deserialize)]
+struct ImageMetadata {
+    job_id: u64,
+    image_number: u32,
+    is_final: bool,
+    /// Seconds since the UNIX epoch.
+    written_at: u64,
+    parameters: JobParameters,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -144,32 +1378,130 @@ enum RunResponse {
     JobQueued { job_id: u64 },
     PaymentRequired,
     Error(String),
+    /// Jobs ahead of this one, plus one; 0 once it is dispatched to a router.
+    /// Answers `RunJob`, then is sent again each time the job moves in the queue.
+    /// `idempotency_key` is the job's, to tell a submitter's jobs apart.
+    QueuePosition { position: usize, idempotency_key: Option<String> },
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 enum AdminRequest {
     SetRouterProcess { process_id: String },
     SetRollupSequencer { address: String },
-    GetRollupState,
+    /// Re-fetch chain state unless it was fetched within `CHAIN_STATE_TTL_SECONDS`
+    /// or `force` is set.
+    GetRollupState {
+        #[serde(default)]
+        force: bool,
+    },
+    SubmitTransaction { transaction: SignedTransaction },
+    SetSigningKey { key: String },
+    CreateProposal { proposal: Proposal },
+    Vote { proposal_hash: u64, is_yea: bool },
+    GetProposals,
+    /// `jitter_percent`, at most 100, is left as is if not given.
+    SetChainRefreshInterval {
+        seconds: u64,
+        #[serde(default)]
+        jitter_percent: Option<u8>,
+    },
+    SaveWorkflow { name: String, json: String },
+    ListWorkflows,
+    DeleteWorkflow { name: String },
+    SetRetentionPolicy { max_jobs_retained: Option<u32>, max_age_hours: Option<u64>, dry_run: bool },
+    SetAllowedClients { nodes: Option<Vec<String>> },
+    SetLogLevel { level: LogLevel },
+    /// Replaces the sequencers set by `SetRollupSequencer`; tried in order.
+    SetRollupSequencers { addresses: Vec<String> },
+    /// An `interval_seconds` of 0 disables health checks.
+    SetRouterHealthCheck { interval_seconds: u64, remove_unhealthy_after_seconds: Option<u64> },
+    GetRouterHealth,
+    /// Images of jobs already writing finish on the old drive.
+    SetImagesDrive { drive: String },
+    GetConfig,
+    /// Drop every queued job, notifying their submitters.
+    ClearQueue,
+    /// Drop `current_job` without waiting for it to time out, and dispatch the next one.
+    ClearCurrentJob,
+    /// `None` lifts the limit.
+    SetClientQuota { max_jobs_per_client: Option<u32> },
+    /// Submit a finished job again, with the same seed, as a new job.
+    ReplayJob { job_id: u64 },
+    /// Set `State.local_parameters`.
+    SetParameters {
+        queue_response_timeout_seconds: u8,
+        serve_timeout_seconds: u16,
+        max_outstanding_payments: u8,
+        payment_period_hours: u8,
+    },
+    /// Cancel our timers, save state, and exit. The runtime restarts the
+    /// process per its `on_exit`, which sets the timers anew.
+    Shutdown,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 enum AdminResponse {
     SetRouterProcess { err: Option<String> },
     SetRollupSequencer { err: Option<String> },
-    GetRollupState { err: Option<String> },
+    /// `fetched_at` is when the chain state now held was fetched.
+    GetRollupState { fetched_at: Option<u64>, err: Option<String> },
+    SubmitTransaction { tx_hash: Option<String>, err: Option<String> },
+    SetSigningKey { err: Option<String> },
+    CreateProposal { proposal_hash: Option<u64>, tx_hash: Option<String>, err: Option<String> },
+    Vote { err: Option<String> },
+    GetProposals(Vec<ProposalSummary>),
+    SetChainRefreshInterval { err: Option<String> },
+    SaveWorkflow { err: Option<String> },
+    ListWorkflows(Vec<String>),
+    DeleteWorkflow { err: Option<String> },
+    SetRetentionPolicy { err: Option<String> },
+    SetAllowedClients { err: Option<String> },
+    SetLogLevel { err: Option<String> },
+    SetRollupSequencers { err: Option<String> },
+    SetRouterHealthCheck { err: Option<String> },
+    GetRouterHealth(Vec<RouterHealthSummary>),
+    SetImagesDrive { err: Option<String> },
+    GetConfig(ClientConfig),
+    ClearQueue { removed: usize },
+    ClearCurrentJob { removed: usize },
+    SetClientQuota { err: Option<String> },
+    Shutdown { err: Option<String> },
+    /// Router `job_id`s are assigned asynchronously, so the replay's queue
+    /// position is given instead (0: dispatched), unless an identical job is
+    /// already running, in which case its `job_id` is given.
+    ReplayJob { queue_position: Option<usize>, job_id: Option<u64>, err: Option<String> },
+    SetParameters { err: Option<String> },
+    /// The request could not be handled at all, e.g. because it came from
+    /// another node.
+    Error(String),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 enum SequencerRequest {
     Read(ReadRequest),
-    //Write(SignedTransaction<OnChainDaoState>),
+    Write(SignedTransaction),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 enum SequencerResponse {
     Read(ReadResponse),
-    Write,  // TODO: return hash of tx?
+    Write { tx_hash: String },
+}
+
+/// A state transition for the sequencer to apply to the DAO
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Transaction {
+    Propose(Proposal),
+    Vote(SignedVote),
+}
+
+/// A transaction signed by the member submitting it
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SignedTransaction {
+    pub from: String,
+    pub transaction: Transaction,
+    /// 65-byte secp256k1 signature over the JSON-serialized `transaction`.
+    pub signature: Vec<u8>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -180,263 +1512,4649 @@ enum ReadRequest {
     Members,
     Proposals,
     Parameters,
+    MemberBlacklist,
+    Stakes,
+    /// The address of one member, by node.
+    Member(String),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 enum ReadResponse {
     All(OnChainDaoState),
-    Dao,
-    Routers(Vec<String>),  // length 1 for now
-    Members(Vec<String>),  // TODO: should probably be the HashMap
-    Proposals,
-    Parameters,
+    Dao(DaoSummary),
+    Routers(Vec<String>),
+    Members(HashMap<String, AlloyAddress>),
+    Proposals(HashMap<u64, ProposalInProgress>),
+    Parameters(DaoParameters),
+    MemberBlacklist(Vec<String>),
+    Stakes(HashMap<String, u64>),
+    /// `None` if the node is not a member.
+    Member(Option<(String, AlloyAddress)>),
 }
 
-fn await_chain_state(state: &mut State) -> anyhow::Result<()> {
-    let Some(rollup_sequencer) = state.rollup_sequencer.clone() else {
-        println!("err: {:?}", state);
-        return Err(anyhow::anyhow!("fetch_chain_state rollup_sequencer must be set before chain state can be fetched"));
-    };
-    Request::to(rollup_sequencer)  // TODO
-        .body(vec![])
-        .blob_bytes(serde_json::to_vec(&SequencerRequest::Read(ReadRequest::All))?)
-        .send_and_await_response(5)??;
-    let Some(LazyLoadBlob { ref bytes, .. }) = get_blob() else {
-        println!("err: no blob");
-        return Err(anyhow::anyhow!("fetch_chain_state didn't get back blob"));
-    };
-    let Ok(SequencerResponse::Read(ReadResponse::All(new_dao_state))) = serde_json::from_slice(bytes) else {
-        println!("err: {:?}", serde_json::from_slice::<serde_json::Value>(bytes));
-        return Err(anyhow::anyhow!("fetch_chain_state got wrong Response back"));
-    };
-    state.on_chain_state = new_dao_state.clone();
-    state.save()?;
-    Ok(())
+/// The governable parameters of the DAO
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct DaoParameters {
+    pub queue_response_timeout_seconds: u8,
+    pub serve_timeout_seconds: u16,
+    pub max_outstanding_payments: u8,
+    pub payment_period_hours: u8,
+    #[serde(default = "default_max_image_bytes")]
+    pub max_image_bytes: u64,
 }
 
-fn handle_public_request(
-    our: &Address,
-    message: &Message,
-    images_dir: &str,
-    state: &mut State,
-) -> anyhow::Result<()> {
-    match serde_json::from_slice(message.body()) {
-        Ok(PublicRequest::RunJob(_job_parameters)) => {
-            if state.current_job.is_some() {
-                return Err(anyhow::anyhow!("wait until current job is done"));
-            }
-            if state.router_process.is_none() {
-                return Err(anyhow::anyhow!("cannot send job until AdminRequest::SetRouterProcess"));
-            };
-            if state.rollup_sequencer.is_none() {
-                return Err(anyhow::anyhow!("cannot send job until AdminRequest::SetRollupSequencer"));
-            };
+/// Defaults for the `DaoParameters` of the same names, set with
+/// `AdminRequest::SetParameters`, so that a node can run before it has read
+/// chain state, e.g. with no sequencer. Each applies only while the chain
+/// leaves its parameter unset (0); 0 here sets no default.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct LocalParameters {
+    pub queue_response_timeout_seconds: u8,
+    pub serve_timeout_seconds: u16,
+    pub max_outstanding_payments: u8,
+    pub payment_period_hours: u8,
+}
 
-            let address = Address::new(
-                state.on_chain_state.routers[0].clone(),
-                state.router_process.clone().unwrap(),
-            );
-            Request::to(address)
-                .body(message.body())
-                .expects_response(20)
-                .send()?;
-        }
-        Ok(PublicRequest::JobUpdate { job_id, is_final, signature }) => {
-            let Some(ref mut current_job) = state.current_job else {
-                println!("unexpectedly got JobUpdate with no current_job set");
-                state.current_job = Some(CurrentJob {
-                    job_id,
-                    next_image_number: 0,
-                });
-                state.save()?;
-                return handle_public_request(our, message, images_dir, state);
-            };
-            let Some(LazyLoadBlob { ref bytes, .. }) = get_blob() else {
-                return Err(anyhow::anyhow!("got PublicRequest::JobUpdate with no blob"));
-            };
-            let file = format!(
-                "{images_dir}/{job_id}-{}.jpg",
-                if is_final { "final".to_string() } else { current_job.next_image_number.to_string() },
-            );
-            current_job.next_image_number += 1;
-            if is_final {
-                // done!
-                state.current_job = None;
-            }
-            state.save()?;
-            let file = vfs::open_file(&file, true, None)?;
-            file.write(bytes)?;
-        }
-        Err(_e) => {
-            return Err(NotAMatchError::NotAMatch.into());
+/// `chain`, unless the chain leaves it unset (0), in which case `local`.
+fn chain_or_local<T: Copy + Default + PartialEq>(chain: T, local: T) -> T {
+    if chain == T::default() {
+        local
+    } else {
+        chain
+    }
+}
+
+/// A lightweight overview of the DAO
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct DaoSummary {
+    pub routers: Vec<String>,
+    pub num_members: usize,
+    pub num_proposals: usize,
+    pub parameters: DaoParameters,
+}
+
+impl ReadResponse {
+    fn answers(&self, read_request: &ReadRequest) -> bool {
+        matches!(
+            (read_request, self),
+            (ReadRequest::All, ReadResponse::All(_))
+                | (ReadRequest::Dao, ReadResponse::Dao(_))
+                | (ReadRequest::Routers, ReadResponse::Routers(_))
+                | (ReadRequest::Members, ReadResponse::Members(_))
+                | (ReadRequest::Proposals, ReadResponse::Proposals(_))
+                | (ReadRequest::Parameters, ReadResponse::Parameters(_))
+                | (ReadRequest::MemberBlacklist, ReadResponse::MemberBlacklist(_))
+                | (ReadRequest::Stakes, ReadResponse::Stakes(_))
+        ) || match (read_request, self) {
+            (ReadRequest::Member(node), ReadResponse::Member(member)) => {
+                member.as_ref().map(|(member_node, _)| member_node == node).unwrap_or(true)
+            }
+            _ => false,
         }
     }
-    Ok(())
 }
 
-fn handle_public_response(
-    message: &Message,
-    state: &mut State,
-) -> anyhow::Result<()> {
-    match serde_json::from_slice(message.body()) {
-        Ok(PublicResponse::RunJob(response)) => {
-            match response {
-                RunResponse::JobQueued { job_id } => {
-                    timer::set_timer(10 * 1000, Some(serde_json::to_vec(&job_id)?)); // TODO
-                    state.current_job = Some(CurrentJob {
-                        job_id,
-                        next_image_number: 0,
-                    });
-                    state.save()?;
-                    println!("get RunResponse::JobQueued for {job_id}");
-                }
-                RunResponse::PaymentRequired => {
-                    println!("got RunResponse::PaymentRequired");
+impl OnChainDaoState {
+    fn parameters(&self) -> DaoParameters {
+        DaoParameters {
+            queue_response_timeout_seconds: self.queue_response_timeout_seconds,
+            serve_timeout_seconds: self.serve_timeout_seconds,
+            max_outstanding_payments: self.max_outstanding_payments,
+            payment_period_hours: self.payment_period_hours,
+            max_image_bytes: self.max_image_bytes,
+        }
+    }
+
+    fn set_parameters(&mut self, parameters: DaoParameters) {
+        self.queue_response_timeout_seconds = parameters.queue_response_timeout_seconds;
+        self.serve_timeout_seconds = parameters.serve_timeout_seconds;
+        self.max_outstanding_payments = parameters.max_outstanding_payments;
+        self.payment_period_hours = parameters.payment_period_hours;
+        self.max_image_bytes = parameters.max_image_bytes;
+    }
+
+    /// Merge whatever slice of the DAO `response` carries into our view.
+    /// Replace our proposals with `proposals`, re-recording each vote through
+    /// `ProposalInProgress::record_vote` against the votes we have already seen,
+    /// so that an overwritten or forged vote is logged and not counted.
+    fn merge_proposals(&mut self, proposals: HashMap<u64, ProposalInProgress>) {
+        let mut old_proposals = std::mem::take(&mut self.proposals);
+        for (proposal_hash, incoming) in proposals {
+            let mut proposal = old_proposals.remove(&proposal_hash).unwrap_or_else(|| {
+                let created_at = if incoming.created_at == 0 { now() } else { incoming.created_at };
+                let expires_at = if incoming.expires_at == 0 {
+                    created_at + PROPOSAL_LIFETIME_SECONDS
+                } else {
+                    incoming.expires_at
+                };
+                ProposalInProgress {
+                    proposal: incoming.proposal,
+                    votes: HashMap::new(),
+                    created_at,
+                    expires_at,
                 }
-                RunResponse::Error(e) => {
-                    println!("got RunResponse::Error: {e}");
+            });
+            for (member, signed_vote) in incoming.votes {
+                if let Err(e) = proposal.record_vote(&member, signed_vote, &self.members) {
+                    log_warn!("ignoring vote on proposal {proposal_hash}: {e}");
                 }
             }
+            self.proposals.insert(proposal_hash, proposal);
         }
-        Ok(PublicResponse::JobUpdate) => {}
-        Err(_e) => {
-            return Err(NotAMatchError::NotAMatch.into());
+    }
+
+    fn merge_read_response(&mut self, response: ReadResponse) {
+        match response {
+            ReadResponse::All(mut new_dao_state) => {
+                let proposals = std::mem::take(&mut new_dao_state.proposals);
+                let old_dao_state = std::mem::replace(self, new_dao_state);
+                self.proposals = old_dao_state.proposals;
+                self.merge_proposals(proposals);
+            }
+            ReadResponse::Dao(summary) => {
+                self.routers = summary.routers;
+                self.set_parameters(summary.parameters);
+            }
+            ReadResponse::Routers(routers) => self.routers = routers,
+            ReadResponse::Members(members) => self.members = members,
+            ReadResponse::Proposals(proposals) => self.merge_proposals(proposals),
+            ReadResponse::Parameters(parameters) => self.set_parameters(parameters),
+            ReadResponse::MemberBlacklist(member_blacklist) => self.member_blacklist = member_blacklist,
+            ReadResponse::Stakes(stakes) => self.stakes = stakes,
+            ReadResponse::Member(Some((node, address))) => {
+                self.members.insert(node, address);
+            }
+            // names no node: `fetch_chain_slice` removes the one asked for
+            ReadResponse::Member(None) => {}
         }
     }
-    Ok(())
 }
 
-fn handle_admin_request(
-    our: &Address,
-    message: &Message,
-    state: &mut State,
-) -> anyhow::Result<()> {
-    let source = message.source();
-    if source.node() != our.node() {
-        if serde_json::from_slice::<AdminRequest>(message.body()).is_err() {
-            return Err(NotAMatchError::NotAMatch.into());
-        }
-        return Err(anyhow::anyhow!("only our can make AdminRequests; rejecting from {source:?}"));
+/// Why `Transport::call_sequencer` failed.
+#[derive(Error, Debug)]
+enum SequencerError {
+    /// The request could not be made at all.
+    #[error("could not send request to sequencer {sequencer}: {reason}")]
+    Request { sequencer: String, reason: String },
+    /// The sequencer did not respond in time, or could not be reached. Worth
+    /// retrying, or trying another sequencer.
+    #[error("sequencer {sequencer} unreachable: {reason}")]
+    Unreachable { sequencer: String, reason: String },
+    /// The sequencer responded, but not with a `SequencerResponse`. Asking
+    /// again will likely get the same answer.
+    #[error("sequencer {sequencer} sent a bad response: {reason}")]
+    BadResponse { sequencer: String, reason: String },
+}
+
+/// How the client reaches the runtime: other processes, its persisted state,
+/// and the VFS files of jobs. Only these calls depend on a live node, so a
+/// stand-in that records them and returns canned answers can drive the job
+/// and chain state logic; see `tests`.
+trait Transport: std::fmt::Debug {
+    /// Send `request` to `sequencer` and wait for its `SequencerResponse`.
+    fn call_sequencer(
+        &self,
+        sequencer: &Address,
+        request: &SequencerRequest,
+    ) -> Result<SequencerResponse, SequencerError>;
+
+    /// Send `request` to `router`, with `blob` as its LazyLoadBlob. If
+    /// `expects_response` is set, the router's response, or a `SendError`,
+    /// arrives later as a message carrying the given context.
+    fn send_to_router(
+        &self,
+        router: &Address,
+        request: &PublicRequest,
+        blob: Option<Vec<u8>>,
+        expects_response: Option<(u64, Vec<u8>)>,
+    ) -> anyhow::Result<()>;
+
+    /// Send a request to a process other than a router, e.g. a submitter.
+    fn send_request(&self, target: &Address, body: Vec<u8>, blob: Option<Vec<u8>>) -> anyhow::Result<()>;
+
+    /// Answer the request being handled.
+    fn respond(&self, body: Vec<u8>) -> anyhow::Result<()>;
+
+    /// Answer the HTTP request being handled.
+    fn respond_http(
+        &self,
+        status: http::StatusCode,
+        headers: Option<HashMap<String, String>>,
+        body: Vec<u8>,
+    ) -> anyhow::Result<()>;
+
+    /// Push `body`, JSON, to the WebSocket `channel_id`.
+    fn push_ws(&self, channel_id: u32, body: Vec<u8>) -> anyhow::Result<()>;
+
+    /// The LazyLoadBlob of the message being handled.
+    fn blob(&self) -> Option<Vec<u8>>;
+
+    /// Have a message with `context` arrive from the timer in `ms`.
+    fn set_timer(&self, ms: u64, context: Vec<u8>);
+
+    /// Block for `ms`, e.g. before retrying a read.
+    fn sleep(&self, ms: u64) -> anyhow::Result<()>;
+
+    /// Persist `bytes` as the process state, read back on restart.
+    fn set_state(&self, bytes: &[u8]);
+
+    /// The process state last persisted, if any.
+    fn state(&self) -> Option<Vec<u8>>;
+
+    fn create_dir(&self, path: &str) -> anyhow::Result<()>;
+
+    /// The entries directly under the directory at `path`.
+    fn read_dir(&self, path: &str) -> anyhow::Result<Vec<vfs::DirEntry>>;
+
+    fn read_file(&self, path: &str) -> anyhow::Result<Vec<u8>>;
+
+    /// Create or overwrite the file at `path`.
+    fn write_file(&self, path: &str, bytes: &[u8]) -> anyhow::Result<()>;
+
+    fn append_file(&self, path: &str, bytes: &[u8]) -> anyhow::Result<()>;
+
+    fn remove_file(&self, path: &str) -> anyhow::Result<()>;
+
+    /// Remove the empty directory at `path`.
+    fn remove_dir(&self, path: &str) -> anyhow::Result<()>;
+}
+
+fn default_transport() -> Box<dyn Transport> {
+    Box::new(KinodeTransport)
+}
+
+/// Talks to the live node.
+#[derive(Debug)]
+struct KinodeTransport;
+
+impl Transport for KinodeTransport {
+    fn call_sequencer(
+        &self,
+        sequencer: &Address,
+        request: &SequencerRequest,
+    ) -> Result<SequencerResponse, SequencerError> {
+        let request_error = |reason: String| SequencerError::Request { sequencer: sequencer.to_string(), reason };
+        let request = serde_json::to_vec(request).map_err(|e| request_error(e.to_string()))?;
+        Request::to(sequencer.clone())
+            .body(vec![])
+            .blob_bytes(request)
+            .send_and_await_response(5)
+            .map_err(|e| request_error(e.to_string()))?
+            .map_err(|e| SequencerError::Unreachable {
+                sequencer: sequencer.to_string(),
+                reason: format!("{:?}", e.kind()),
+            })?;
+        let bad_response = |reason: String| SequencerError::BadResponse { sequencer: sequencer.to_string(), reason };
+        let Some(LazyLoadBlob { ref bytes, .. }) = get_blob() else {
+            log_error!("sequencer response has no blob");
+            return Err(bad_response("no blob".to_string()));
+        };
+        serde_json::from_slice(bytes).map_err(|e| {
+            log_debug!("unparseable sequencer response: {:?}", serde_json::from_slice::<serde_json::Value>(bytes));
+            bad_response(format!("unparseable Response: {e}"))
+        })
     }
-    match serde_json::from_slice(message.body()) {
-        Ok(AdminRequest::SetRouterProcess { process_id }) => {
-            let process_id = process_id.parse()?;
-            state.router_process = Some(process_id);
-            state.save()?;
-            Response::new()
-                .body(serde_json::to_vec(&AdminResponse::SetRouterProcess { err: None })?)
-                .send()?;
+
+    fn send_to_router(
+        &self,
+        router: &Address,
+        request: &PublicRequest,
+        blob: Option<Vec<u8>>,
+        expects_response: Option<(u64, Vec<u8>)>,
+    ) -> anyhow::Result<()> {
+        let mut request = Request::to(router.clone()).body(serde_json::to_vec(request)?);
+        if let Some(blob) = blob {
+            request = request.blob_bytes(blob);
         }
-        Ok(AdminRequest::SetRollupSequencer { address }) => {
-            let address = address.parse()?;
-            state.rollup_sequencer = Some(address);
-            state.save()?;
-            await_chain_state(state)?;
-            Response::new()
-                .body(serde_json::to_vec(&AdminResponse::SetRollupSequencer { err: None })?)
-                .send()?;
-        }
-        Ok(AdminRequest::GetRollupState) => {
-            if state.rollup_sequencer.is_none() {
-                let err = "no rollup sequencer set";
-                Response::new()
-                    .body(serde_json::to_vec(&AdminResponse::GetRollupState {
-                        err: Some(err.to_string())
-                    })?)
-                    .send()?;
-                return Err(anyhow::anyhow!(err));
-            }
-            await_chain_state(state)?;
-            Response::new()
-                .body(serde_json::to_vec(&AdminResponse::GetRollupState { err: None })?)
-                .send()?;
+        match expects_response {
+            Some((timeout, context)) => request.expects_response(timeout).context(context).send()?,
+            None => request.send()?,
         }
-        Err(e) => {
-            return Err(NotAMatchError::NotAMatch.into());
+        Ok(())
+    }
+
+    fn send_request(&self, target: &Address, body: Vec<u8>, blob: Option<Vec<u8>>) -> anyhow::Result<()> {
+        let request = Request::to(target.clone()).body(body);
+        match blob {
+            Some(blob) => request.blob_bytes(blob).send(),
+            None => request.send(),
         }
     }
-    Ok(())
+
+    fn respond(&self, body: Vec<u8>) -> anyhow::Result<()> {
+        Response::new().body(body).send()
+    }
+
+    fn respond_http(
+        &self,
+        status: http::StatusCode,
+        headers: Option<HashMap<String, String>>,
+        body: Vec<u8>,
+    ) -> anyhow::Result<()> {
+        http::send_response(status, headers, body)
+    }
+
+    fn push_ws(&self, channel_id: u32, body: Vec<u8>) -> anyhow::Result<()> {
+        let blob = LazyLoadBlob { mime: Some("application/json".to_string()), bytes: body };
+        http::send_ws_push(channel_id, http::WsMessageType::Text, blob)
+    }
+
+    fn blob(&self) -> Option<Vec<u8>> {
+        get_blob().map(|blob| blob.bytes)
+    }
+
+    fn set_timer(&self, ms: u64, context: Vec<u8>) {
+        timer::set_timer(ms, Some(context));
+    }
+
+    fn sleep(&self, ms: u64) -> anyhow::Result<()> {
+        timer::set_and_await_timer(ms)?;
+        Ok(())
+    }
+
+    fn set_state(&self, bytes: &[u8]) {
+        set_state(bytes);
+    }
+
+    fn state(&self) -> Option<Vec<u8>> {
+        get_state()
+    }
+
+    fn create_dir(&self, path: &str) -> anyhow::Result<()> {
+        vfs::open_dir(path, true, None)?;
+        Ok(())
+    }
+
+    fn read_dir(&self, path: &str) -> anyhow::Result<Vec<vfs::DirEntry>> {
+        Ok(vfs::open_dir(path, false, None)?.read()?)
+    }
+
+    fn read_file(&self, path: &str) -> anyhow::Result<Vec<u8>> {
+        Ok(vfs::open_file(path, false, None)?.read()?)
+    }
+
+    fn write_file(&self, path: &str, bytes: &[u8]) -> anyhow::Result<()> {
+        Ok(vfs::open_file(path, true, None)?.write(bytes)?)
+    }
+
+    fn append_file(&self, path: &str, bytes: &[u8]) -> anyhow::Result<()> {
+        Ok(vfs::open_file(path, true, None)?.append(bytes)?)
+    }
+
+    fn remove_file(&self, path: &str) -> anyhow::Result<()> {
+        Ok(vfs::remove_file(path, None)?)
+    }
+
+    fn remove_dir(&self, path: &str) -> anyhow::Result<()> {
+        Ok(vfs::remove_dir(path, None)?)
+    }
 }
 
-fn handle_message(
-    our: &Address,
-    message: &Message,
-    images_dir: &str,
-    state: &mut State,
-) -> anyhow::Result<()> {
-    if message.is_request() {
-        match handle_admin_request(our, message, state) {
-            Ok(_) => return Ok(()),
-            Err(e) => {
-                if e.downcast_ref::<NotAMatchError>().is_none() {
-                    return Err(e);
-                }
+/// A side effect of handling a message that can wait until the handler
+/// returns: responding, messages to other processes, timers, saving state,
+/// and thumbnails. Handlers queue these on `State.effects` for `run_effects`.
+/// Writing an image and calling the sequencer are not effects: what the
+/// handler does next depends on their outcome, so it makes them directly
+/// through `State.transport`.
+#[derive(Debug)]
+enum Effect {
+    /// The response to the request being handled.
+    Respond(Vec<u8>),
+    /// The response to the HTTP request being handled.
+    RespondHttp { status: http::StatusCode, headers: Option<HashMap<String, String>>, body: Vec<u8> },
+    /// A request to a process other than a router, e.g. a submitter.
+    SendRequest { target: Address, body: Vec<u8>, blob: Option<Vec<u8>> },
+    /// See `Transport::send_to_router`.
+    SendToRouter {
+        router: Address,
+        request: PublicRequest,
+        blob: Option<Vec<u8>>,
+        expects_response: Option<(u64, Vec<u8>)>,
+    },
+    SetTimer { ms: u64, context: Vec<u8> },
+    WriteFile { path: String, bytes: Vec<u8> },
+    /// See `State::persist`; `State::save` queues one at most.
+    SaveState,
+    /// Push to every open `UPDATES_WS_PATH` channel.
+    PushImageEvent(ImageEvent),
+}
+
+/// Perform the effects queued by the message just handled, in order. A failed
+/// effect is logged and does not stop those after it, nor undo the state
+/// change that queued it.
+fn run_effects(state: &mut State) {
+    for effect in std::mem::take(&mut state.effects) {
+        let result = match effect {
+            Effect::Respond(body) => state.transport.respond(body),
+            Effect::RespondHttp { status, headers, body } => state.transport.respond_http(status, headers, body),
+            Effect::SendRequest { target, body, blob } => state.transport.send_request(&target, body, blob),
+            Effect::SendToRouter { router, request, blob, expects_response } => {
+                state.transport.send_to_router(&router, &request, blob, expects_response)
+            }
+            Effect::SetTimer { ms, context } => {
+                state.transport.set_timer(ms, context);
+                Ok(())
             }
+            Effect::WriteFile { path, bytes } => state
+                .transport
+                .write_file(&path, &bytes)
+                .map_err(|e| anyhow::anyhow!("failed to write {path}: {e:#}")),
+            Effect::SaveState => state.persist().map_err(|e| e.context("failed to save state")),
+            Effect::PushImageEvent(event) => {
+                push_image_event(state, &event);
+                Ok(())
+            }
+        };
+        if let Err(e) = result {
+            log_error!("effect failed: {e:?}");
         }
-        match handle_public_request(our, message, images_dir, state) {
-            Ok(_) => return Ok(()),
-            Err(e) => {
-                if e.downcast_ref::<NotAMatchError>().is_none() {
-                    return Err(e);
+    }
+}
+
+/// Send a read to each of `rollup_sequencers` in turn, recording the one that
+/// answers as `active_sequencer`. If none answers, the round is retried with
+/// backoff, up to `SEQUENCER_READ_ATTEMPTS` rounds, so this is bounded. Only
+/// reads are retried: resending a write could submit it twice.
+/// A sequencer that responds badly is not retried, nor another tried in its stead.
+fn read_from_sequencer(
+    state: &mut State,
+    read_request: &ReadRequest,
+) -> anyhow::Result<SequencerResponse> {
+    if state.rollup_sequencers.is_empty() {
+        return Err(anyhow::anyhow!("rollup_sequencers must be set before sequencer can be contacted"));
+    }
+    let request = SequencerRequest::Read(read_request.clone());
+    let mut attempt = 1;
+    loop {
+        let mut last_error = None;
+        for sequencer in state.rollup_sequencers.clone() {
+            match state.transport.call_sequencer(&sequencer, &request) {
+                Ok(response) => {
+                    state.active_sequencer = Some(sequencer);
+                    return Ok(response);
                 }
+                Err(e @ SequencerError::Unreachable { .. }) => {
+                    log_warn!("sequencer {sequencer} read attempt {attempt} failed: {e}");
+                    last_error = Some(e);
+                }
+                Err(e) => return Err(e.into()),
             }
         }
-        return Err(anyhow::anyhow!(
-            "unexpected request from {:?}: {:?}",
-            message.source(),
-            serde_json::from_slice::<serde_json::Value>(message.body()),
-        ));
+        let last_error = last_error.expect("rollup_sequencers is not empty");
+        if attempt >= SEQUENCER_READ_ATTEMPTS {
+            return Err(last_error.into());
+        }
+        let delay_ms = SEQUENCER_RETRY_BASE_MS << (attempt - 1);
+        log_warn!("no sequencer answered; retrying in {delay_ms}ms");
+        state.transport.sleep(delay_ms)?;
+        attempt += 1;
     }
-    match handle_public_response(message, state) {
-        Ok(_) => return Ok(()),
-        Err(e) => {
-            if e.downcast_ref::<NotAMatchError>().is_none() {
-                return Err(e);
+}
+
+fn await_chain_state(state: &mut State) -> anyhow::Result<()> {
+    fetch_chain_slice(state, ReadRequest::All)
+}
+
+fn chain_state_is_fresh(state: &State) -> bool {
+    state
+        .last_chain_fetch
+        .map(|fetched_at| now().saturating_sub(fetched_at) < CHAIN_STATE_TTL_SECONDS)
+        .unwrap_or(false)
+}
+
+/// `await_chain_state` unless the last fetch finished within
+/// `CHAIN_STATE_TTL_SECONDS`, so that refreshes triggered close together, e.g.
+/// a periodic refresh and a `GetRollupState`, result in one fetch. A fetch
+/// blocks the message loop, so one never starts while another is underway.
+fn refresh_chain_state(state: &mut State) -> anyhow::Result<()> {
+    if chain_state_is_fresh(state) {
+        log_debug!("chain state fetched at {:?} is fresh; coalescing refresh", state.last_chain_fetch);
+        return Ok(());
+    }
+    await_chain_state(state)
+}
+
+/// Fetch only the slice of the DAO named by `read_request` and merge it into
+/// `on_chain_state`. Cheaper than `await_chain_state` on large DAOs. If a
+/// proposal changing the sequencer passes, the whole DAO is read again from
+/// the new sequencer, at most once per call.
+fn fetch_chain_slice(state: &mut State, mut read_request: ReadRequest) -> anyhow::Result<()> {
+    let mut refetched = false;
+    loop {
+        let SequencerResponse::Read(read_response) = read_from_sequencer(state, &read_request)? else {
+            return Err(anyhow::anyhow!("fetch_chain_slice got wrong Response back"));
+        };
+        if !read_response.answers(&read_request) {
+            return Err(anyhow::anyhow!(
+                "fetch_chain_slice asked for {read_request:?} but got a different ReadResponse back"
+            ));
+        }
+        if let ReadResponse::All(OnChainDaoState { ref proposals, .. })
+            | ReadResponse::Proposals(ref proposals) = read_response
+        {
+            for (proposal_hash, proposal) in proposals.iter() {
+                let expected_hash = hash_proposal(&proposal.proposal);
+                if *proposal_hash != expected_hash {
+                    log_warn!("proposal stored at {proposal_hash} hashes to {expected_hash}");
+                }
             }
         }
+        if let ReadResponse::All(_) = read_response {
+            state.last_chain_fetch = Some(now());
+        }
+        if let (ReadRequest::Member(node), ReadResponse::Member(None)) = (&read_request, &read_response) {
+            state.on_chain_state.members.remove(node);
+        }
+        state.on_chain_state.merge_read_response(read_response);
+        let sequencer = state.rollup_sequencers.first().cloned();
+        apply_passed_proposals(state);
+        expire_proposals(state);
+        state.save();
+        arm_payment_timer(state)?;
+        arm_chain_refresh_timer(state)?;
+        if state.rollup_sequencers.first() == sequencer.as_ref() {
+            return Ok(());
+        }
+        // sequencers whose DAOs each name another cannot keep us fetching
+        if refetched {
+            log_warn!(
+                "sequencer changed again, to {:?}; reading from it from the next refresh",
+                state.rollup_sequencers.first(),
+            );
+            return Ok(());
+        }
+        log_info!("sequencer changed to {:?}; re-fetching chain state", state.rollup_sequencers.first());
+        read_request = ReadRequest::All;
+        refetched = true;
     }
-    if message.source().to_string() == format!("{}@timer:distro:sys", our.node()) {
-        let Some(ref current_job) = state.current_job else {
-            // job already finished
+}
+
+/// Timers cannot be cancelled outright: move to a new `timer_epoch` so that
+/// those already set are ignored when they fire, and let them be set again.
+fn cancel_timers(state: &mut State) {
+    state.timer_epoch += 1;
+    state.payment_timer_armed = false;
+    state.chain_refresh_timer_armed = false;
+    state.router_health_timer_armed = false;
+    state.image_gc_timer_armed = false;
+}
+
+fn set_timer(state: &mut State, kind: TimerKind, ms: u64) -> anyhow::Result<()> {
+    let context = TimerContext { kind, epoch: state.timer_epoch };
+    state.effects.push(Effect::SetTimer { ms, context: serde_json::to_vec(&context)? });
+    Ok(())
+}
+
+/// `ms` moved by up to `percent` percent either way, by an amount drawn from
+/// `entropy`.
+fn jittered(ms: u64, percent: u8, entropy: u64) -> u64 {
+    // widened so that neither the spread nor the interval it moves overflows
+    let (ms, entropy) = (ms as u128, entropy as u128);
+    let spread = ms * percent.min(100) as u128 / 100;
+    if spread == 0 {
+        return ms as u64;
+    }
+    let offset = entropy % (2 * spread + 1);
+    (ms - spread + offset).clamp(1, u64::MAX as u128) as u64
+}
+
+/// Set a timer to re-fetch chain state, if one is not already set.
+fn arm_chain_refresh_timer(state: &mut State) -> anyhow::Result<()> {
+    if state.chain_refresh_timer_armed
+        || state.chain_refresh_interval_seconds == 0
+        || state.rollup_sequencers.is_empty()
+    {
+        return Ok(());
+    }
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    let interval_ms = jittered(
+        state.chain_refresh_interval_seconds.saturating_mul(1000),
+        state.chain_refresh_jitter_percent,
+        generate_seed(&nanos.to_string()),
+    );
+    set_timer(state, TimerKind::ChainRefresh, interval_ms)?;
+    state.chain_refresh_timer_armed = true;
+    Ok(())
+}
+
+/// Set a timer to ping the routers, if one is not already set.
+fn arm_router_health_timer(state: &mut State) -> anyhow::Result<()> {
+    if state.router_health_timer_armed || state.router_health_interval_seconds == 0 {
+        return Ok(());
+    }
+    let interval_ms = state.router_health_interval_seconds.saturating_mul(1000);
+    set_timer(state, TimerKind::RouterHealth, interval_ms)?;
+    state.router_health_timer_armed = true;
+    Ok(())
+}
+
+/// Ping every router. Replies and `SendError`s, matched by their `RouterPing`
+/// context, are passed to `record_router_health`. Routers do not implement
+/// `Ping`, so what counts is whether the ping reaches the router's node: any
+/// reply, or a timeout, means it did; only an `Offline` error means it did not.
+fn ping_routers(state: &mut State) -> anyhow::Result<()> {
+    for router in state.on_chain_state.routers.iter() {
+        let Some(address) = router_address(state, router) else {
             return Ok(());
         };
-        let timer_job_id: u64 = serde_json::from_slice(message.context().unwrap_or_default())?;
-        if current_job.job_id == timer_job_id {
-            state.current_job = None;
-            state.save()?;
-            return Err(anyhow::anyhow!("job {} timed out", timer_job_id));
+        let context = serde_json::to_vec(&RouterPing { router: router.clone() })?;
+        state.effects.push(Effect::SendToRouter {
+            router: address,
+            request: PublicRequest::Ping,
+            blob: None,
+            expects_response: Some((ROUTER_PING_TIMEOUT_SECONDS, context)),
+        });
+    }
+    Ok(())
+}
+
+fn record_router_health(state: &mut State, router: &str, is_healthy: bool) {
+    let health = state.router_health.entry(router.to_string()).or_default();
+    if is_healthy {
+        health.last_seen = Some(now());
+        health.unhealthy_since = None;
+        health.removal_proposed = false;
+        state.unhealthy_routers.remove(router);
+    } else {
+        if health.unhealthy_since.is_none() {
+            log_warn!("router {router} failed a health check");
+            health.unhealthy_since = Some(now());
+        }
+        state.unhealthy_routers.insert(router.to_string());
+    }
+}
+
+/// Propose removing each router that has been unhealthy for longer than
+/// `remove_unhealthy_routers_after_seconds`, once per period of ill health.
+fn propose_removing_unhealthy_routers(our: &Address, state: &mut State) -> anyhow::Result<()> {
+    let Some(remove_after) = state.remove_unhealthy_routers_after_seconds else {
+        return Ok(());
+    };
+    let now = now();
+    let routers: Vec<String> = state
+        .router_health
+        .iter()
+        .filter(|(router, health)| {
+            !health.removal_proposed
+                && health
+                    .unhealthy_since
+                    .map(|since| now.saturating_sub(since) >= remove_after)
+                    .unwrap_or(false)
+                && state.on_chain_state.routers.contains(router)
+        })
+        .map(|(router, _)| router.clone())
+        .collect();
+    for router in routers {
+        let proposal = Proposal::RemoveRouter(router.clone());
+        if !state.on_chain_state.proposals.contains_key(&hash_proposal(&proposal)) {
+            let tx_hash = sign_transaction(our, state, Transaction::Propose(proposal))
+                .and_then(|transaction| submit_transaction(state, transaction))?;
+            log_info!("proposed removing unhealthy router {router}: {tx_hash}");
+        }
+        if let Some(health) = state.router_health.get_mut(&router) {
+            health.removal_proposed = true;
         }
     }
     Ok(())
 }
 
-call_init!(init);
-fn init(our: Address) {
-    println!("{}: begin", our.process());
+/// Set a timer to garbage-collect images, if one is not already set and the
+/// retention policy sets a limit.
+fn arm_image_gc_timer(state: &mut State) -> anyhow::Result<()> {
+    if state.image_gc_timer_armed || state.retention_policy.is_unlimited() {
+        return Ok(());
+    }
+    set_timer(state, TimerKind::ImageGc, IMAGE_GC_INTERVAL_SECONDS * 1000)?;
+    state.image_gc_timer_armed = true;
+    Ok(())
+}
 
-    let images_dir = vfs::create_drive(our.package_id(), "images", None).unwrap();
-    let mut state = State::load();
+/// When the job in `job_dir` last saved an image, per the `ImageMetadata`
+/// sidecars there.
+fn last_written_at(state: &State, job_dir: &str) -> Option<u64> {
+    state
+        .transport
+        .read_dir(job_dir)
+        .ok()?
+        .into_iter()
+        .filter_map(|entry| {
+            let file = entry.path.rsplit('/').next()?;
+            if !file.ends_with(".json") {
+                return None;
+            }
+            let bytes = state.transport.read_file(&format!("{job_dir}/{file}")).ok()?;
+            serde_json::from_slice::<ImageMetadata>(&bytes).ok()
+        })
+        .map(|metadata| metadata.written_at)
+        .max()
+}
 
-    loop {
-        let message = match await_message() {
-            Ok(m) => m,
-            Err(_send_err) => {
-                println!("SendError");
-                state.current_job = None;
-                state.save().unwrap();
+/// Delete the job directories in `images_dir` that `state.retention_policy`
+/// does not retain, oldest first. A job's age is taken from `job_history`,
+/// or, for jobs no longer in the history, from when it last saved an image;
+/// directories with neither are left alone, as are flat images from before
+/// per-job directories.
+fn collect_garbage(images_dir: &str, state: &State) -> anyhow::Result<()> {
+    let policy = &state.retention_policy;
+    let current_job_id = state.current_job.as_ref().and_then(|current_job| current_job.job_id);
+    let finished_at: HashMap<u64, u64> = state
+        .job_history
+        .iter()
+        .filter_map(|record| Some((record.job_id?, record.finished_at)))
+        .collect();
+    let mut job_dirs: Vec<(u64, u64)> = state
+        .transport
+        .read_dir(images_dir)?
+        .into_iter()
+        .filter(|entry| entry.file_type == vfs::FileType::Directory)
+        .filter_map(|entry| entry.path.rsplit('/').next()?.parse::<u64>().ok())
+        .filter(|job_id| Some(*job_id) != current_job_id)
+        .filter_map(|job_id| {
+            let finished_at = finished_at
+                .get(&job_id)
+                .copied()
+                .or_else(|| last_written_at(state, &format!("{images_dir}/{job_id}")));
+            if finished_at.is_none() {
+                log_debug!("image gc: skipping {images_dir}/{job_id}, whose age is unknown");
+            }
+            Some((finished_at?, job_id))
+        })
+        .collect();
+    job_dirs.sort();
+
+    let excess = policy
+        .max_jobs_retained
+        .map(|max| job_dirs.len().saturating_sub(max as usize))
+        .unwrap_or(0);
+    let expired_before = policy
+        .max_age_hours
+        .map(|hours| now().saturating_sub(hours * 3600));
+    for (i, (finished_at, job_id)) in job_dirs.into_iter().enumerate() {
+        let is_expired = expired_before.map(|before| finished_at < before).unwrap_or(false);
+        if i >= excess && !is_expired {
+            continue;
+        }
+        let job_dir = format!("{images_dir}/{job_id}");
+        if policy.dry_run {
+            log_info!("image gc (dry run): would delete {job_dir}");
+            continue;
+        }
+        for entry in state.transport.read_dir(&job_dir)? {
+            let Some(file) = entry.path.rsplit('/').next() else {
                 continue;
-            },
-        };
-        match handle_message(
-            &our,
-            &message,
-            &images_dir,
-            &mut state,
-        ) {
-            Ok(()) => {}
-            Err(e) => {
-                println!("{}: error: {:?}", our.process(), e);
+            };
+            state.transport.remove_file(&format!("{job_dir}/{file}"))?;
+        }
+        state.transport.remove_dir(&job_dir)?;
+        log_info!("image gc: deleted {job_dir}");
+    }
+    Ok(())
+}
+
+/// Reject proposals that could never be applied.
+fn check_proposal(proposal: &Proposal) -> anyhow::Result<()> {
+    match proposal {
+        Proposal::AddMember { address, .. } => {
+            AlloyAddress::from_str(address)
+                .map_err(|e| anyhow::anyhow!("invalid member address {address}: {e}"))?;
+        }
+        Proposal::ChangeMaxImageBytes(0) => {
+            return Err(anyhow::anyhow!("max image bytes must be positive"));
+        }
+        Proposal::ChangeSequencer(address) => {
+            Address::from_str(address)
+                .map_err(|e| anyhow::anyhow!("invalid sequencer address {address}: {e:?}"))?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Apply the effect of a passed `proposal` to our view of the DAO.
+fn apply_proposal(state: &mut State, proposal: &Proposal) {
+    let dao = &mut state.on_chain_state;
+    match proposal {
+        Proposal::ChangeRootNode(node) => dao.routers = vec![node.clone()],
+        Proposal::ChangeQueueResponseTimeoutSeconds(seconds) => {
+            dao.queue_response_timeout_seconds = *seconds;
+        }
+        // takes effect from the next serve timer armed
+        Proposal::ChangeServeTimeoutSeconds(seconds) => dao.serve_timeout_seconds = *seconds,
+        Proposal::ChangeMaxOutstandingPayments(max) => dao.max_outstanding_payments = *max,
+        Proposal::ChangePaymentPeriodHours(hours) => dao.payment_period_hours = *hours,
+        // an image already partly received is held to the new limit by its remaining chunks
+        Proposal::ChangeMaxImageBytes(max) => dao.max_image_bytes = *max,
+        Proposal::Kick(node) => {
+            dao.members.remove(node);
+        }
+        Proposal::AddRouter(node) => {
+            if !dao.routers.contains(node) {
+                dao.routers.push(node.clone());
+            }
+        }
+        Proposal::RemoveRouter(node) => {
+            if dao.routers.len() == 1 && dao.routers[0] == *node {
+                log_warn!("not removing {node}: it is the last router");
+                return;
+            }
+            dao.routers.retain(|router| router != node);
+            state.unhealthy_routers.remove(node);
+        }
+        // tried before any sequencers set locally, which are kept as fallbacks
+        Proposal::ChangeSequencer(address) => match Address::from_str(address) {
+            Ok(address) => {
+                if state.rollup_sequencers.first() != Some(&address) {
+                    state.rollup_sequencers.retain(|sequencer| *sequencer != address);
+                    state.rollup_sequencers.insert(0, address);
+                    state.active_sequencer = None;
+                }
+            }
+            Err(e) => log_warn!("not changing sequencer: bad address {address}: {e:?}"),
+        },
+        Proposal::AddMember { node, address } => match AlloyAddress::from_str(address) {
+            Ok(address) => {
+                dao.members.insert(node.clone(), address);
             }
+            Err(e) => log_warn!("not adding member {node}: bad address {address}: {e}"),
+        },
+    }
+}
+
+/// Drop proposals that have expired without passing, remembering them in
+/// `expired_proposals` so that they are not re-added by the next fetch.
+fn expire_proposals(state: &mut State) {
+    let now = now();
+    let dao = &mut state.on_chain_state;
+    let expired: Vec<u64> = dao
+        .proposals
+        .iter()
+        .filter(|(proposal_hash, proposal)| {
+            proposal.expires_at <= now || state.expired_proposals.contains(*proposal_hash)
+        })
+        .map(|(proposal_hash, _)| *proposal_hash)
+        .collect();
+    for proposal_hash in expired {
+        let Some(proposal) = dao.proposals.remove(&proposal_hash) else {
+            continue;
+        };
+        if state.expired_proposals.insert(proposal_hash) {
+            let tally = tally(&proposal, &dao.members, &dao.member_blacklist, &dao.stakes);
+            log_info!(
+                "proposal {proposal_hash} expired and failed ({} yea, {} nay{}): {:?}",
+                tally.yea,
+                tally.nay,
+                if tally.quorum_reached { "" } else { ", no quorum" },
+                proposal.proposal,
+            );
+        }
+    }
+}
+
+/// Apply, then drop, any proposals whose tally has passed, oldest first so
+/// that proposals passing together apply in the same order on every client.
+/// They are remembered in `applied_proposals`, so that when the next fetch
+/// re-adds them they are dropped rather than applied again.
+fn apply_passed_proposals(state: &mut State) {
+    let dao = &state.on_chain_state;
+    let mut passed: Vec<(u64, u64)> = dao
+        .proposals
+        .iter()
+        .filter(|(proposal_hash, proposal)| {
+            state.applied_proposals.contains(*proposal_hash)
+                || tally(proposal, &dao.members, &dao.member_blacklist, &dao.stakes).passed
+        })
+        .map(|(proposal_hash, proposal)| (proposal.created_at, *proposal_hash))
+        .collect();
+    passed.sort();
+    for (_, proposal_hash) in passed {
+        let Some(proposal) = state.on_chain_state.proposals.remove(&proposal_hash) else {
+            continue;
+        };
+        if !state.applied_proposals.insert(proposal_hash) {
+            log_debug!("dropping already applied proposal {proposal_hash}");
+            continue;
+        }
+        log_info!("applying passed proposal {proposal_hash}: {:?}", proposal.proposal);
+        apply_proposal(state, &proposal.proposal);
+    }
+}
+
+/// The first 8 bytes (big-endian) of the keccak256 of the JSON-serialized `proposal`.
+/// This is the key of `OnChainDaoState.proposals` and the `Vote.proposal_hash`.
+fn hash_proposal(proposal: &Proposal) -> u64 {
+    let bytes = serde_json::to_vec(proposal).expect("Proposal always serializes");
+    let digest = keccak256(bytes);
+    let mut prefix = [0u8; 8];
+    prefix.copy_from_slice(&digest[..8]);
+    u64::from_be_bytes(prefix)
+}
+
+/// The wallet for our DAO member account, checked against our on-chain member
+/// address. Blacklisted members may not sign, so cannot propose or vote.
+fn member_wallet(our: &Address, state: &State) -> anyhow::Result<LocalWallet> {
+    let Some(ref signing_key) = state.signing_key else {
+        return Err(anyhow::anyhow!("cannot sign until AdminRequest::SetSigningKey"));
+    };
+    let Some(member_address) = state.on_chain_state.members.get(our.node()) else {
+        return Err(anyhow::anyhow!("{} is not a DAO member", our.node()));
+    };
+    if state.on_chain_state.member_blacklist.iter().any(|member| member == our.node()) {
+        return Err(anyhow::anyhow!("{} is blacklisted by the DAO", our.node()));
+    }
+    let wallet: LocalWallet = signing_key.parse()?;
+    if &wallet.address() != member_address {
+        return Err(anyhow::anyhow!(
+            "signing key address {} does not match member address {member_address}",
+            wallet.address(),
+        ));
+    }
+    Ok(wallet)
+}
+
+/// Sign `vote` as our DAO member account.
+fn sign_vote(our: &Address, state: &State, vote: Vote) -> anyhow::Result<SignedVote> {
+    let wallet = member_wallet(our, state)?;
+    let signature = wallet.sign_message_sync(&serde_json::to_vec(&vote)?)?;
+    Ok(SignedVote {
+        vote,
+        signature: signature.as_bytes().to_vec(),
+    })
+}
+
+/// Sign `transaction` as our DAO member account.
+fn sign_transaction(
+    our: &Address,
+    state: &State,
+    transaction: Transaction,
+) -> anyhow::Result<SignedTransaction> {
+    let wallet = member_wallet(our, state)?;
+    let signature = wallet.sign_message_sync(&serde_json::to_vec(&transaction)?)?;
+    Ok(SignedTransaction {
+        from: our.node().to_string(),
+        transaction,
+        signature: signature.as_bytes().to_vec(),
+    })
+}
+
+/// Submit a signed transaction to the sequencer, returning its tx hash.
+fn submit_transaction(state: &State, transaction: SignedTransaction) -> anyhow::Result<String> {
+    let Some(sequencer) = state.active_sequencer.as_ref().or(state.rollup_sequencers.first()) else {
+        return Err(anyhow::anyhow!("rollup_sequencers must be set before sequencer can be contacted"));
+    };
+    let SequencerResponse::Write { tx_hash } =
+        state.transport.call_sequencer(sequencer, &SequencerRequest::Write(transaction))?
+    else {
+        return Err(anyhow::anyhow!("submit_transaction got wrong Response back"));
+    };
+    Ok(tx_hash)
+}
+
+/// Seconds since the UNIX epoch.
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn router_address(state: &State, router_node: &str) -> Option<Address> {
+    let router_process = state.router_process.clone()?;
+    Some(Address::new(router_node, router_process))
+}
+
+/// Pick the next router round-robin, skipping unhealthy routers unless every
+/// router is unhealthy.
+fn select_router(state: &mut State) -> Option<Address> {
+    let routers = &state.on_chain_state.routers;
+    if routers.is_empty() {
+        return None;
+    }
+    let num_routers = routers.len();
+    let start = state.router_cursor % num_routers;
+    let index = (0..num_routers)
+        .map(|offset| (start + offset) % num_routers)
+        .find(|index| !state.unhealthy_routers.contains(&routers[*index]))
+        .unwrap_or(start);
+    state.router_cursor = (index + 1) % num_routers;
+    router_address(state, &state.on_chain_state.routers[index])
+}
+
+fn current_router_address(state: &State) -> Option<Address> {
+    router_address(state, &state.current_job.as_ref()?.router)
+}
+
+/// Zero `outstanding_payments` once `payment_period_hours` have passed.
+/// A `payment_period_hours` of 0 means payment periods never end.
+fn reset_payment_period_if_elapsed(state: &mut State) {
+    let period_seconds = payment_period_seconds(state);
+    if period_seconds == 0 {
+        return;
+    }
+    let now = now();
+    if now.saturating_sub(state.payment_period_started_at) >= period_seconds {
+        state.outstanding_payments = 0;
+        state.payment_period_started_at = now;
+    }
+}
+
+/// Set a timer for the end of the current payment period, if one is not already set.
+/// A `payment_period_hours` of 0 disables the timer.
+fn arm_payment_timer(state: &mut State) -> anyhow::Result<()> {
+    if state.payment_timer_armed {
+        return Ok(());
+    }
+    let period_seconds = payment_period_seconds(state);
+    if period_seconds == 0 {
+        return Ok(());
+    }
+    let period_ends_at = state.payment_period_started_at + period_seconds;
+    let remaining_seconds = period_ends_at.saturating_sub(now()).max(1);
+    set_timer(state, TimerKind::PaymentReset, remaining_seconds * 1000)?;
+    state.payment_timer_armed = true;
+    Ok(())
+}
+
+/// Send the job at the front of `job_queue` to the router, if no job is in flight.
+fn dispatch_next_job(state: &mut State) -> anyhow::Result<()> {
+    if state.current_job.is_some() {
+        return Ok(());
+    }
+    if state.job_queue.is_empty() {
+        return Ok(());
+    }
+    let Some(router) = select_router(state) else {
+        return Ok(());
+    };
+    let router = router.node().to_string();
+    let Some(queued_job) = state.job_queue.pop_front() else {
+        return Ok(());
+    };
+
+    state.current_job = Some(CurrentJob {
+        job_id: None,
+        parameters: queued_job.parameters,
+        submitter: queued_job.submitter,
+        router: router.clone(),
+        routers_tried: vec![router],
+        next_image_number: 0,
+        queued_at: queued_job.queued_at,
+        retries: 0,
+        next_attempt_at: None,
+        timer_generation: 0,
+        content_hash: queued_job.content_hash,
+        images_dir: None,
+        dispatched_at: Some(now()),
+        partial_image: None,
+        subscribers: vec![],
+        looked_up_router: None,
+    });
+    notify_queue_positions(state, 0)?;
+    send_current_job(state)
+}
+
+/// Tell the submitters of the queued jobs from index `from` on their positions.
+fn notify_queue_positions(state: &mut State, from: usize) -> anyhow::Result<()> {
+    for (index, queued_job) in state.job_queue.iter().enumerate().skip(from) {
+        let position = RunResponse::QueuePosition {
+            position: index + 1,
+            idempotency_key: queued_job.parameters.idempotency_key.clone(),
+        };
+        notify_submitter(&mut state.effects, &queued_job.submitter, &PublicResponse::RunJob(position))?;
+    }
+    Ok(())
+}
+
+/// Send `current_job` to the router. The request context is the job's timer
+/// generation so that a `SendError` can be matched to the attempt that failed.
+fn send_current_job(state: &mut State) -> anyhow::Result<()> {
+    let Some(router) = current_router_address(state) else {
+        return Err(anyhow::anyhow!("cannot send job: no router"));
+    };
+    let input_images = match state.current_job {
+        Some(ref current_job) if !current_job.parameters.input_images.is_empty() => {
+            match read_input_images(state, &current_job.parameters.input_images) {
+                Ok(bytes) => Some(bytes),
+                Err(e) => {
+                    let error = format!("failed to read input images: {e}");
+                    log_error!("{error}");
+                    notify_submitter(
+                        &mut state.effects,
+                        &current_job.submitter,
+                        &PublicResponse::run_job_error(error.clone()),
+                    )?;
+                    return finish_current_job(state, JobOutcome::Failed(error));
+                }
+            }
+        }
+        _ => None,
+    };
+    let queue_response_timeout = queue_response_timeout_seconds(state);
+    // give the expects_response timeout a head start over the timer so that an
+    //  unresponsive router results in a retry rather than a dropped job
+    arm_job_timer(state, JobPhase::Queue, queue_response_timeout + 1)?;
+    let Some(ref mut current_job) = state.current_job else {
+        return Ok(());
+    };
+    current_job.next_attempt_at = None;
+    state.effects.push(Effect::SendToRouter {
+        router,
+        request: PublicRequest::RunJob(current_job.parameters.clone()),
+        blob: input_images,
+        expects_response: Some((queue_response_timeout, serde_json::to_vec(&current_job.timer_generation)?)),
+    });
+    state.save();
+    Ok(())
+}
+
+/// Move `current_job` to the next router it has not yet been dispatched to.
+/// Once every router has been tried, drop the job and notify its submitter.
+fn fail_over_current_job(state: &mut State, reason: &str) -> anyhow::Result<()> {
+    let Some(ref mut current_job) = state.current_job else {
+        return Ok(());
+    };
+    let next_router = state
+        .on_chain_state
+        .routers
+        .iter()
+        .find(|router| !current_job.routers_tried.contains(router))
+        .cloned();
+    let Some(next_router) = next_router else {
+        state.unhealthy_routers.insert(current_job.router.clone());
+        let error = format!(
+            "all routers failed ({}); last error: {reason}",
+            current_job.routers_tried.join(", "),
+        );
+        log_warn!("dropping job: {error}");
+        notify_submitter(
+            &mut state.effects,
+            &current_job.submitter,
+            &PublicResponse::run_job_error(error.clone()),
+        )?;
+        return finish_current_job(state, JobOutcome::Failed(error));
+    };
+    log_warn!("router {} failed ({reason}); failing over to {next_router}", current_job.router);
+    state.unhealthy_routers.insert(current_job.router.clone());
+    state.metrics.routers.entry(current_job.router.clone()).or_default().failovers += 1;
+    current_job.router = next_router.clone();
+    current_job.routers_tried.push(next_router);
+    current_job.dispatched_at = Some(now());
+    current_job.retries = 0;
+    send_current_job(state)
+}
+
+fn is_http_submitter(submitter: &Address) -> bool {
+    submitter.process.to_string() == "http_server:distro:sys"
+}
+
+/// Terminal notifications are sent after the submitter's `RunJob` has already
+/// been handled, so they go out as new requests carrying a `PublicResponse`.
+/// Jobs submitted over HTTP follow their progress on the updates WebSocket instead.
+fn notify_submitter(
+    effects: &mut Vec<Effect>,
+    submitter: &Address,
+    response: &PublicResponse,
+) -> anyhow::Result<()> {
+    if is_http_submitter(submitter) {
+        return Ok(());
+    }
+    effects.push(Effect::SendRequest { target: submitter.clone(), body: serde_json::to_vec(response)?, blob: None });
+    Ok(())
+}
+
+/// Answer the request being handled with `response` once it has been.
+fn respond<T: Serialize>(effects: &mut Vec<Effect>, response: &T) -> anyhow::Result<()> {
+    effects.push(Effect::Respond(serde_json::to_vec(response)?));
+    Ok(())
+}
+
+/// Answer the HTTP request being handled once it has been.
+fn respond_http(
+    effects: &mut Vec<Effect>,
+    status: http::StatusCode,
+    headers: Option<HashMap<String, String>>,
+    body: Vec<u8>,
+) -> anyhow::Result<()> {
+    effects.push(Effect::RespondHttp { status, headers, body });
+    Ok(())
+}
+
+/// Push `event` to each of the job's `subscribers`.
+fn notify_subscribers(effects: &mut Vec<Effect>, current_job: &CurrentJob, event: JobEvent) -> anyhow::Result<()> {
+    let Some(job_id) = current_job.job_id else {
+        return Ok(());
+    };
+    let body = serde_json::to_vec(&PublicResponse::JobEvent { job_id, event })?;
+    for subscriber in current_job.subscribers.iter() {
+        effects.push(Effect::SendRequest { target: subscriber.clone(), body: body.clone(), blob: None });
+    }
+    Ok(())
+}
+
+fn queue_response_timeout_seconds(state: &State) -> u64 {
+    match chain_or_local(
+        state.on_chain_state.queue_response_timeout_seconds,
+        state.local_parameters.queue_response_timeout_seconds,
+    ) {
+        0 => DEFAULT_QUEUE_RESPONSE_TIMEOUT_SECONDS,
+        seconds => seconds as u64,
+    }
+}
+
+fn serve_timeout_seconds(state: &State) -> u64 {
+    match chain_or_local(
+        state.on_chain_state.serve_timeout_seconds,
+        state.local_parameters.serve_timeout_seconds,
+    ) {
+        0 => DEFAULT_SERVE_TIMEOUT_SECONDS,
+        seconds => seconds as u64,
+    }
+}
+
+/// 0 means no limit.
+fn max_outstanding_payments(state: &State) -> u8 {
+    chain_or_local(
+        state.on_chain_state.max_outstanding_payments,
+        state.local_parameters.max_outstanding_payments,
+    )
+}
+
+/// 0 means payment periods never end.
+fn payment_period_seconds(state: &State) -> u64 {
+    chain_or_local(state.on_chain_state.payment_period_hours, state.local_parameters.payment_period_hours) as u64
+        * 3600
+}
+
+/// Set a timeout timer for `current_job`, superseding any previous one.
+fn arm_job_timer(state: &mut State, phase: JobPhase, seconds: u64) -> anyhow::Result<()> {
+    let generation = state.next_timer_generation;
+    let Some(ref mut current_job) = state.current_job else {
+        return Ok(());
+    };
+    state.next_timer_generation += 1;
+    current_job.timer_generation = generation;
+    let job_timer = JobTimer { job_id: current_job.job_id, phase, generation };
+    set_timer(state, TimerKind::Job(job_timer), seconds * 1000)
+}
+
+/// Timers do not survive a restart: re-arm the one `current_job` was waiting
+/// on, or, if no job is in flight, dispatch the next queued job.
+fn resume_jobs(state: &mut State) -> anyhow::Result<()> {
+    let Some(ref current_job) = state.current_job else {
+        return dispatch_next_job(state);
+    };
+    let (phase, seconds) = match (current_job.job_id, current_job.next_attempt_at) {
+        (Some(_), _) => (JobPhase::Serve, serve_timeout_seconds(state)),
+        (None, Some(next_attempt_at)) => {
+            (JobPhase::Retry, next_attempt_at.saturating_sub(now()).max(1))
+        }
+        (None, None) => (JobPhase::Queue, queue_response_timeout_seconds(state)),
+    };
+    log_info!("resuming job {:?} in phase {phase:?}; timing out in {seconds}s", current_job.job_id);
+    arm_job_timer(state, phase, seconds)?;
+    state.save();
+    Ok(())
+}
+
+/// Drop `current_job` and move on to the next queued job. This also disarms
+/// the job's timeout timer: the timer handler ignores timers for jobs that
+/// are not current.
+fn finish_current_job(state: &mut State, outcome: JobOutcome) -> anyhow::Result<()> {
+    if let Some(current_job) = state.current_job.take() {
+        let finished_at = now();
+        notify_subscribers(
+            &mut state.effects,
+            &current_job,
+            JobEvent::Finished { outcome: outcome.clone(), images_received: current_job.next_image_number },
+        )?;
+        let metrics = &mut state.metrics;
+        let router_metrics = metrics.routers.entry(current_job.router.clone()).or_default();
+        match outcome {
+            JobOutcome::Completed => {
+                let latency = finished_at.saturating_sub(current_job.queued_at);
+                metrics.jobs_completed += 1;
+                metrics.serve_latency_seconds_sum += latency;
+                router_metrics.jobs_completed += 1;
+                router_metrics.serve_latency_seconds_sum += latency;
+                let dispatched_at = current_job.dispatched_at.unwrap_or(current_job.queued_at);
+                router_metrics.record_serve_latency(finished_at.saturating_sub(dispatched_at));
+            }
+            JobOutcome::Failed(_) => {
+                metrics.jobs_failed += 1;
+                router_metrics.jobs_failed += 1;
+            }
+            JobOutcome::TimedOut => {
+                metrics.jobs_timed_out += 1;
+                router_metrics.jobs_timed_out += 1;
+            }
+            JobOutcome::Cancelled => {
+                metrics.jobs_cancelled += 1;
+                router_metrics.jobs_cancelled += 1;
+            }
+        }
+        // a job that never got a job_id may be retried under the same key
+        if let (Some(job_id), Some(key)) = (current_job.job_id, current_job.parameters.idempotency_key.as_ref()) {
+            state.idempotency_keys.insert(
+                idempotency_scope(&current_job.submitter, key),
+                IdempotentJob { job_id, finished_at },
+            );
+        }
+        release_input_images(state, &current_job.parameters.input_images);
+        let mut job_parameters = current_job.parameters;
+        state.job_history.push_back(JobRecord {
+            job_id: current_job.job_id,
+            workflow: std::mem::take(&mut job_parameters.workflow),
+            image_count: current_job.next_image_number,
+            outcome,
+            queued_at: current_job.queued_at,
+            finished_at,
+            seed: job_parameters.seed,
+            job_parameters: Some(job_parameters),
+        });
+        while state.job_history.len() > MAX_JOB_HISTORY {
+            state.job_history.pop_front();
+        }
+    }
+    state.save();
+    dispatch_next_job(state)
+}
+
+/// Write a chunk of a chunked `JobUpdate` to `image_path`, appending to the
+/// chunks before it. Returns the hex-encoded sha256 of the whole image once
+/// its last chunk is written, else `None`.
+fn receive_chunk(
+    transport: &dyn Transport,
+    current_job: &mut CurrentJob,
+    job_id: u64,
+    job_dir: &str,
+    image_path: &str,
+    chunk: &ImageChunk,
+    bytes: &[u8],
+    max_image_bytes: u64,
+) -> Result<Option<String>, JobError> {
+    let write_failed = |e: anyhow::Error| JobError::WriteFailed {
+        job_id,
+        path: image_path.to_string(),
+        reason: format!("{e:#}"),
+    };
+    if chunk.chunk_index == 0 && chunk.image_number == current_job.next_image_number {
+        // also restarts an image whose earlier chunks were lost
+        let output_format = current_job.parameters.output_format;
+        if !output_format.matches(bytes) {
+            return Err(JobError::WrongFormat { job_id, expected: output_format });
+        }
+        if chunk.image_number == 0 {
+            transport.create_dir(job_dir).map_err(write_failed)?;
+        }
+        current_job.partial_image = Some(PartialImage {
+            image_number: chunk.image_number,
+            next_chunk_index: 0,
+            bytes_received: 0,
+            hasher: Sha256::new(),
+        });
+    }
+    let Some(ref mut partial_image) = current_job.partial_image else {
+        return Err(JobError::ChunkOutOfOrder {
+            job_id,
+            image_number: chunk.image_number,
+            chunk_index: chunk.chunk_index,
+            expected_image_number: current_job.next_image_number,
+            expected_chunk_index: 0,
+        });
+    };
+    if (chunk.image_number, chunk.chunk_index) != (partial_image.image_number, partial_image.next_chunk_index) {
+        return Err(JobError::ChunkOutOfOrder {
+            job_id,
+            image_number: chunk.image_number,
+            chunk_index: chunk.chunk_index,
+            expected_image_number: partial_image.image_number,
+            expected_chunk_index: partial_image.next_chunk_index,
+        });
+    }
+    let size = partial_image.bytes_received + bytes.len() as u64;
+    if size > max_image_bytes {
+        current_job.partial_image = None;
+        let _ = transport.remove_file(image_path);
+        return Err(JobError::TooLarge { job_id, size, limit: max_image_bytes });
+    }
+    if chunk.chunk_index == 0 {
+        transport.write_file(image_path, bytes).map_err(write_failed)?;
+    } else {
+        transport.append_file(image_path, bytes).map_err(write_failed)?;
+    }
+    partial_image.hasher.update(bytes);
+    partial_image.bytes_received = size;
+    partial_image.next_chunk_index += 1;
+    if !chunk.is_last_chunk {
+        return Ok(None);
+    }
+    let image_sha256 = hex::encode(partial_image.hasher.clone().finalize());
+    current_job.partial_image = None;
+    Ok(Some(image_sha256))
+}
+
+/// Write `bytes` to `{file_stem}.{ext}` and `metadata` to `{file_stem}.json`.
+/// `bytes` is `None` if the image was already written by `receive_chunk`.
+/// If either cannot be written, the image is removed so that a partial image
+/// is never served and the two never get out of sync.
+fn write_image(
+    transport: &dyn Transport,
+    file_stem: &str,
+    output_format: ImageFormat,
+    bytes: Option<&[u8]>,
+    metadata: &ImageMetadata,
+) -> anyhow::Result<()> {
+    let image_path = format!("{file_stem}.{}", output_format.extension());
+    let metadata_path = format!("{file_stem}.json");
+    let metadata = serde_json::to_vec_pretty(metadata)?;
+
+    if let Some(bytes) = bytes {
+        if let Err(e) = transport.write_file(&image_path, bytes) {
+            let _ = transport.remove_file(&image_path);
+            return Err(anyhow::anyhow!("failed to write {image_path}: {e:#}"));
+        }
+    }
+    if let Err(e) = transport.write_file(&metadata_path, &metadata) {
+        let _ = transport.remove_file(&image_path);
+        return Err(anyhow::anyhow!("failed to write {metadata_path}: {e:#}"));
+    }
+    Ok(())
+}
+
+/// Decode `bytes`, downscale to fit within `THUMBNAIL_MAX_DIMENSION`, and
+/// re-encode as JPEG.
+fn make_thumbnail(output_format: ImageFormat, bytes: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let format = match output_format {
+        ImageFormat::Png => image::ImageFormat::Png,
+        ImageFormat::Jpg => image::ImageFormat::Jpeg,
+        ImageFormat::Webp => image::ImageFormat::WebP,
+    };
+    let thumbnail = image::load_from_memory_with_format(bytes, format)?
+        .thumbnail(THUMBNAIL_MAX_DIMENSION, THUMBNAIL_MAX_DIMENSION)
+        // JPEG has no alpha channel
+        .to_rgb8();
+    let mut encoded = std::io::Cursor::new(vec![]);
+    image::DynamicImage::ImageRgb8(thumbnail)
+        .write_to(&mut encoded, image::ImageOutputFormat::Jpeg(THUMBNAIL_JPEG_QUALITY))?;
+    Ok(encoded.into_inner())
+}
+
+/// Decode a JPEG and re-encode it at `quality`.
+fn reencode_jpeg(bytes: &[u8], quality: u8) -> anyhow::Result<Vec<u8>> {
+    let image = image::load_from_memory_with_format(bytes, image::ImageFormat::Jpeg)?;
+    let mut encoded = std::io::Cursor::new(vec![]);
+    image.write_to(&mut encoded, image::ImageOutputFormat::Jpeg(quality))?;
+    Ok(encoded.into_inner())
+}
+
+/// Workflows are saved as `{workflows_dir}/{name}.json`; names are restricted
+/// so that they cannot escape the drive.
+fn workflow_path(workflows_dir: &str, name: &str) -> anyhow::Result<String> {
+    let is_valid = !name.is_empty()
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !is_valid {
+        return Err(anyhow::anyhow!(
+            "invalid workflow name {name:?}: use only letters, digits, `-`, and `_`"
+        ));
+    }
+    Ok(format!("{workflows_dir}/{name}.json"))
+}
+
+fn load_workflow(state: &State, workflows_dir: &str, name: &str) -> anyhow::Result<String> {
+    let path = workflow_path(workflows_dir, name)?;
+    let bytes = state
+        .transport
+        .read_file(&path)
+        .map_err(|_| anyhow::anyhow!("no saved workflow named {name:?}"))?;
+    Ok(String::from_utf8(bytes)?)
+}
+
+/// Whether `workflow` is a ComfyUI API workflow graph rather than the name of a
+/// router-side workflow.
+fn is_workflow_graph(workflow: &str) -> bool {
+    workflow.trim_start().starts_with('{')
+}
+
+/// A seed derived from `entropy`: the first 8 bytes of its keccak256, big-endian.
+fn generate_seed(entropy: &str) -> u64 {
+    let digest = keccak256(entropy.as_bytes());
+    let mut prefix = [0u8; 8];
+    prefix.copy_from_slice(&digest[..8]);
+    u64::from_be_bytes(prefix)
+}
+
+/// Set each of the `names` inputs present on any node of `workflow`, a ComfyUI
+/// API workflow graph, to `value`.
+fn apply_inputs(workflow: &str, names: &[&str], value: serde_json::Value) -> anyhow::Result<String> {
+    let mut workflow: serde_json::Value = serde_json::from_str(workflow)?;
+    if let Some(nodes) = workflow.as_object_mut() {
+        for node in nodes.values_mut() {
+            let Some(inputs) = node.get_mut("inputs").and_then(|i| i.as_object_mut()) else {
+                continue;
+            };
+            for name in names {
+                if let Some(input) = inputs.get_mut(*name) {
+                    *input = value.clone();
+                }
+            }
+        }
+    }
+    Ok(serde_json::to_string(&workflow)?)
+}
+
+/// Inject `parameters`, a JSON object of `"{node_id}.{input}": value`, into the
+/// inputs of the nodes of `workflow`, a ComfyUI API workflow graph.
+fn apply_parameters(workflow: &str, parameters: &str) -> anyhow::Result<String> {
+    let mut workflow: serde_json::Value = serde_json::from_str(workflow)?;
+    let parameters: serde_json::Map<String, serde_json::Value> = serde_json::from_str(parameters)?;
+    for (key, value) in parameters {
+        let Some((node_id, input)) = key.split_once('.') else {
+            return Err(anyhow::anyhow!(
+                "parameter {key:?} must be of the form node_id.input"
+            ));
+        };
+        let Some(node) = workflow.get_mut(node_id) else {
+            return Err(anyhow::anyhow!("parameter {key:?} targets nonexistent node {node_id}"));
+        };
+        let Some(inputs) = node
+            .as_object_mut()
+            .map(|node| node.entry("inputs").or_insert_with(|| serde_json::json!({})))
+            .and_then(|inputs| inputs.as_object_mut())
+        else {
+            return Err(anyhow::anyhow!("node {node_id} has malformed inputs"));
+        };
+        inputs.insert(input.to_string(), value);
+    }
+    Ok(serde_json::to_string(&workflow)?)
+}
+
+/// Pre-flight checks so malformed jobs fail before being queued.
+/// `workflow` is either the name of a router-side workflow or a ComfyUI API
+/// workflow: a JSON object of node id to `{"class_type", "inputs", ...}`.
+/// Unknown fields are allowed. Returns every problem found.
+fn validate_job(job_parameters: &JobParameters) -> Vec<String> {
+    let mut errors = vec![];
+    let workflow = job_parameters.workflow.trim();
+    if workflow.is_empty() {
+        errors.push("workflow is empty".to_string());
+    } else if is_workflow_graph(workflow) {
+        match serde_json::from_str::<serde_json::Value>(workflow) {
+            Err(e) => errors.push(format!("workflow is not valid JSON: {e}")),
+            Ok(serde_json::Value::Object(nodes)) => {
+                for (node_id, node) in nodes.iter() {
+                    let has_class_type = node
+                        .get("class_type")
+                        .map(|class_type| class_type.is_string())
+                        .unwrap_or(false);
+                    if !has_class_type {
+                        errors.push(format!("workflow node {node_id} has no string class_type"));
+                    }
+                }
+            }
+            Ok(_) => errors.push("workflow is not a JSON object".to_string()),
+        }
+    }
+    match serde_json::from_str::<serde_json::Value>(&job_parameters.parameters) {
+        Err(e) => errors.push(format!("parameters is not valid JSON: {e}")),
+        Ok(serde_json::Value::Object(_)) => {}
+        Ok(_) => errors.push("parameters is not a JSON object".to_string()),
+    }
+    if job_parameters.priority > MAX_JOB_PRIORITY {
+        errors.push(format!("priority must be at most {MAX_JOB_PRIORITY}"));
+    }
+    if job_parameters.batch_size == Some(0) {
+        errors.push("batch_size must be at least 1".to_string());
+    }
+    if let Some(ref key) = job_parameters.idempotency_key {
+        if key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY_LEN {
+            errors.push(format!("idempotency_key must be 1 to {MAX_IDEMPOTENCY_KEY_LEN} bytes"));
+        }
+    }
+    if let Some(jpeg_quality) = job_parameters.jpeg_quality {
+        if !(1..=100).contains(&jpeg_quality) {
+            errors.push("jpeg_quality must be 1 to 100".to_string());
+        }
+        if job_parameters.output_format != ImageFormat::Jpg {
+            errors.push("jpeg_quality requires output_format Jpg".to_string());
+        }
+    }
+    if job_parameters.input_images.len() > MAX_INPUT_IMAGES {
+        errors.push(format!("at most {MAX_INPUT_IMAGES} input_images may be given"));
+    }
+    for input_image in job_parameters.input_images.iter() {
+        let name = &input_image.name;
+        let is_valid = !name.is_empty()
+            && !name.starts_with('.')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+        if !is_valid {
+            errors.push(format!("invalid input image name {name:?}: use only letters, digits, `-`, `_`, and `.`"));
+        }
+        if input_image.size == 0 {
+            errors.push(format!("input image {name} is empty"));
+        }
+    }
+    if let Some(ref name) = job_parameters.name {
+        if name.len() > MAX_WORKFLOW_NAME_LEN {
+            errors.push(format!("name must be at most {MAX_WORKFLOW_NAME_LEN} bytes"));
+        }
+    }
+    errors
+}
+
+/// Why `client` may not submit jobs, if it may not.
+fn client_rejection(our: &Address, state: &State, client: &Address) -> Option<String> {
+    let node = client.node();
+    if state.on_chain_state.client_blacklist.iter().any(|banned| banned == node) {
+        return Some(format!("{node} is blacklisted by the DAO"));
+    }
+    if node == our.node() {
+        return None;
+    }
+    if let Some(ref allowed_clients) = state.allowed_clients {
+        if !allowed_clients.iter().any(|allowed| allowed == node) {
+            return Some(format!("{node} is not allowed to run jobs on this client"));
+        }
+    }
+    None
+}
+
+/// Identifies resubmissions of the same job: the hex-encoded sha256 of the
+/// workflow and parameters as submitted, before any substitution.
+fn job_content_hash(job_parameters: &JobParameters) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(job_parameters.workflow.as_bytes());
+    hasher.update([0]);
+    hasher.update(job_parameters.parameters.as_bytes());
+    for input_image in job_parameters.input_images.iter() {
+        hasher.update([0]);
+        hasher.update(input_image.sha256.as_deref().unwrap_or_default().as_bytes());
+    }
+    hex::encode(hasher.finalize())
+}
+
+/// Replace a `@name` workflow with the workflow saved under `name`.
+fn resolve_workflow(state: &State, job_parameters: &mut JobParameters, workflows_dir: &str) -> Result<(), String> {
+    if let Some(name) = job_parameters.workflow.strip_prefix(WORKFLOW_REFERENCE_PREFIX) {
+        let name = name.to_string();
+        job_parameters.workflow = load_workflow(state, workflows_dir, &name).map_err(|e| e.to_string())?;
+        job_parameters.name.get_or_insert(name);
+    }
+    Ok(())
+}
+
+/// For a workflow graph, substitute `seed`, then `batch_size`, then
+/// `parameters` into its nodes. Other workflows are left to the router.
+fn substitute_graph(job_parameters: &mut JobParameters, seed: u64) -> anyhow::Result<()> {
+    if !is_workflow_graph(&job_parameters.workflow) {
+        return Ok(());
+    }
+    let mut workflow = apply_inputs(&job_parameters.workflow, &["seed", "noise_seed"], seed.into())?;
+    if let Some(batch_size) = job_parameters.batch_size {
+        workflow = apply_inputs(&workflow, &["batch_size"], batch_size.into())?;
+    }
+    job_parameters.workflow = apply_parameters(&workflow, &job_parameters.parameters)?;
+    Ok(())
+}
+
+/// Every reason `submit_job` would reject `job_parameters` as invalid. Does
+/// not consider whether the client is configured or has room to run it.
+fn check_job(state: &State, mut job_parameters: JobParameters, workflows_dir: &str) -> Vec<String> {
+    if let Err(e) = resolve_workflow(state, &mut job_parameters, workflows_dir) {
+        return vec![e];
+    }
+    let errors = validate_job(&job_parameters);
+    if !errors.is_empty() {
+        return errors;
+    }
+    // any seed will do: substitution only fails on the shape of the graph
+    let seed = job_parameters.seed.unwrap_or_default();
+    match substitute_graph(&mut job_parameters, seed) {
+        Ok(()) => vec![],
+        Err(e) => vec![e.to_string()],
+    }
+}
+
+/// Jobs `node` has queued or in flight.
+fn client_job_count(state: &State, node: &str) -> usize {
+    let in_flight = state
+        .current_job
+        .as_ref()
+        .map(|current_job| current_job.submitter.node() == node)
+        .unwrap_or(false);
+    let queued = state
+        .job_queue
+        .iter()
+        .filter(|queued_job| queued_job.submitter.node() == node)
+        .count();
+    queued + in_flight as usize
+}
+
+/// Keys are only unique per submitting node.
+fn idempotency_scope(submitter: &Address, key: &str) -> String {
+    format!("{}:{key}", submitter.node())
+}
+
+/// What to respond to a resubmission with idempotency key `key`, if the
+/// original job is in flight, queued, or recently finished.
+fn find_idempotent_job(
+    state: &State,
+    submitter: &Address,
+    key: &str,
+) -> Option<Result<usize, RunResponse>> {
+    let is_original = |parameters: &JobParameters, original_submitter: &Address| {
+        parameters.idempotency_key.as_deref() == Some(key) && original_submitter.node() == submitter.node()
+    };
+    if let Some(ref current_job) = state.current_job {
+        if is_original(&current_job.parameters, &current_job.submitter) {
+            return Some(match current_job.job_id {
+                Some(job_id) => Err(RunResponse::JobQueued { job_id }),
+                None => Ok(0),
+            });
+        }
+    }
+    if let Some(index) = state
+        .job_queue
+        .iter()
+        .position(|queued_job| is_original(&queued_job.parameters, &queued_job.submitter))
+    {
+        return Some(Ok(index + 1));
+    }
+    state
+        .idempotency_keys
+        .get(&idempotency_scope(submitter, key))
+        .map(|job| Err(RunResponse::JobQueued { job_id: job.job_id }))
+}
+
+/// Split `bytes`, the blob of a `RunJob`, into the job's `input_images`,
+/// checking each and filling in its `sha256`.
+fn split_input_images(
+    job_parameters: &mut JobParameters,
+    bytes: Option<&[u8]>,
+    max_image_bytes: u64,
+) -> Result<Vec<Vec<u8>>, String> {
+    if job_parameters.input_images.is_empty() {
+        return Ok(vec![]);
+    }
+    let Some(bytes) = bytes else {
+        return Err("input_images must be sent in the LazyLoadBlob of a RunJob request".to_string());
+    };
+    let total = job_parameters
+        .input_images
+        .iter()
+        .try_fold(0u64, |total, input_image| total.checked_add(input_image.size))
+        .ok_or_else(|| "input_images sizes add up to more than 2^64 bytes".to_string())?;
+    if total != bytes.len() as u64 {
+        return Err(format!("input_images add up to {total} bytes, but the blob has {}", bytes.len()));
+    }
+    let mut images = vec![];
+    let mut rest = bytes;
+    for input_image in job_parameters.input_images.iter_mut() {
+        let name = &input_image.name;
+        if input_image.size > max_image_bytes {
+            return Err(format!("input image {name} is {} bytes; limit is {max_image_bytes}", input_image.size));
+        }
+        let (image, after) = rest.split_at(input_image.size as usize);
+        rest = after;
+        let is_image = [ImageFormat::Png, ImageFormat::Jpg, ImageFormat::Webp]
+            .iter()
+            .any(|format| format.matches(image));
+        if !is_image {
+            return Err(format!("input image {name} is not a PNG, JPEG, or WebP image"));
+        }
+        input_image.sha256 = Some(hex::encode(Sha256::digest(image)));
+        images.push(image.to_vec());
+    }
+    Ok(images)
+}
+
+fn input_image_path(state: &State, input_image: &InputImage) -> anyhow::Result<String> {
+    let (Some(inputs_dir), Some(sha256)) = (state.inputs_dir.as_ref(), input_image.sha256.as_ref()) else {
+        return Err(anyhow::anyhow!("input image {} is not staged", input_image.name));
+    };
+    Ok(format!("{inputs_dir}/{sha256}"))
+}
+
+/// The staged `input_images`, concatenated, to send with a job.
+fn read_input_images(state: &State, input_images: &[InputImage]) -> anyhow::Result<Vec<u8>> {
+    let mut bytes = vec![];
+    for input_image in input_images {
+        let path = input_image_path(state, input_image)?;
+        bytes.extend(state.transport.read_file(&path)?);
+    }
+    Ok(bytes)
+}
+
+/// Delete the staged `input_images` of a job that will not be sent again,
+/// except those the current job or a queued job also takes.
+fn release_input_images(state: &State, input_images: &[InputImage]) {
+    let pending = state
+        .current_job
+        .iter()
+        .map(|current_job| &current_job.parameters)
+        .chain(state.job_queue.iter().map(|queued_job| &queued_job.parameters));
+    let pending: Vec<&JobParameters> = pending.collect();
+    for input_image in input_images {
+        let still_needed = pending.iter().any(|job_parameters| {
+            job_parameters.input_images.iter().any(|pending| pending.sha256 == input_image.sha256)
+        });
+        if still_needed {
+            continue;
+        }
+        if let Ok(path) = input_image_path(state, input_image) {
+            if let Err(e) = state.transport.remove_file(&path) {
+                log_warn!("failed to remove staged input image {path}: {e:?}");
+            }
+        }
+    }
+}
+
+/// Enqueue a job, dispatching it if nothing is in flight. Returns the job's
+/// queue position (0: dispatched), or the `RunResponse` to respond with instead:
+/// a rejection, or `JobQueued` if the same submitter already has an identical
+/// job running. An identical job already queued is not queued again; its
+/// position is returned. Likewise for a job with the same `idempotency_key`,
+/// which may also have finished. `input_bytes` is the blob carrying the
+/// job's `input_images`, if any.
+fn submit_job(
+    state: &mut State,
+    mut job_parameters: JobParameters,
+    input_bytes: Option<&[u8]>,
+    submitter: Address,
+    workflows_dir: &str,
+) -> anyhow::Result<Result<usize, RunResponse>> {
+    state.metrics.jobs_submitted += 1;
+    if let Err(e) = resolve_workflow(state, &mut job_parameters, workflows_dir) {
+        return Ok(Err(RunResponse::Error(e)));
+    }
+    let errors = validate_job(&job_parameters);
+    if !errors.is_empty() {
+        return Ok(Err(RunResponse::Error(format!("invalid job: {}", errors.join("; ")))));
+    }
+    let max_image_bytes = state.on_chain_state.max_image_bytes;
+    let input_images = match split_input_images(&mut job_parameters, input_bytes, max_image_bytes) {
+        Ok(input_images) => input_images,
+        Err(e) => return Ok(Err(RunResponse::Error(format!("invalid job: {e}")))),
+    };
+    if let Some(ref key) = job_parameters.idempotency_key {
+        let now = now();
+        state
+            .idempotency_keys
+            .retain(|_, job| now.saturating_sub(job.finished_at) < IDEMPOTENCY_KEY_TTL_SECONDS);
+        if let Some(response) = find_idempotent_job(state, &submitter, key) {
+            log_info!("returning original job for idempotency key {key}");
+            return Ok(response);
+        }
+    }
+    let content_hash = job_content_hash(&job_parameters);
+    if let Some(ref current_job) = state.current_job {
+        if current_job.content_hash == content_hash && current_job.submitter.node() == submitter.node() {
+            log_info!("collapsing duplicate of current job {:?}", current_job.job_id);
+            return Ok(match current_job.job_id {
+                Some(job_id) => Err(RunResponse::JobQueued { job_id }),
+                None => Ok(0),
+            });
+        }
+    }
+    let duplicate_position = state.job_queue.iter().position(|queued_job| {
+        queued_job.content_hash == content_hash && queued_job.submitter.node() == submitter.node()
+    });
+    if let Some(index) = duplicate_position {
+        log_info!("collapsing duplicate of queued job at position {}", index + 1);
+        return Ok(Ok(index + 1));
+    }
+    let seed = match job_parameters.seed {
+        Some(seed) => seed,
+        None => {
+            state.seed_nonce += 1;
+            generate_seed(&format!("{submitter}:{}:{}", now(), state.seed_nonce))
+        }
+    };
+    job_parameters.seed = Some(seed);
+    if let Err(e) = substitute_graph(&mut job_parameters, seed) {
+        return Ok(Err(RunResponse::Error(format!("invalid job: {e}"))));
+    }
+    if state.router_process.is_none() {
+        return Ok(Err(RunResponse::Error(
+            "cannot send job until AdminRequest::SetRouterProcess".to_string(),
+        )));
+    };
+    if state.rollup_sequencers.is_empty() {
+        return Ok(Err(RunResponse::Error(
+            "cannot send job until AdminRequest::SetRollupSequencer".to_string(),
+        )));
+    };
+    if state.on_chain_state.routers.is_empty() {
+        // e.g. chain state has not been fetched yet: queued jobs would never dispatch
+        return Ok(Err(RunResponse::Error("no routers configured".to_string())));
+    }
+    reset_payment_period_if_elapsed(state);
+    let max_outstanding_payments = max_outstanding_payments(state);
+    if max_outstanding_payments > 0 && state.outstanding_payments >= max_outstanding_payments {
+        return Ok(Err(RunResponse::PaymentRequired));
+    }
+    if state.job_queue.len() >= MAX_QUEUE_DEPTH {
+        return Ok(Err(RunResponse::Error(format!(
+            "job queue is full ({MAX_QUEUE_DEPTH} jobs); try again later"
+        ))));
+    }
+    if let Some(max_jobs_per_client) = state.max_jobs_per_client {
+        if client_job_count(state, submitter.node()) >= max_jobs_per_client as usize {
+            return Ok(Err(RunResponse::Error(format!(
+                "{} already has {max_jobs_per_client} jobs queued or running; try again later",
+                submitter.node(),
+            ))));
+        }
+    }
+    for (index, (input_image, bytes)) in job_parameters.input_images.iter().zip(input_images).enumerate() {
+        let staged = input_image_path(state, input_image)
+            .and_then(|path| state.transport.write_file(&path, &bytes));
+        if let Err(e) = staged {
+            log_error!("failed to stage input image {}: {e:?}", input_image.name);
+            // the job will not run, so neither will those staged before this one
+            release_input_images(state, &job_parameters.input_images[..index]);
+            return Ok(Err(RunResponse::Error(format!("failed to stage input image {}", input_image.name))));
+        }
+    }
+
+    // behind every job of at least its priority, so ties go by submission time
+    let index = state
+        .job_queue
+        .iter()
+        .position(|queued_job| queued_job.parameters.priority < job_parameters.priority)
+        .unwrap_or(state.job_queue.len());
+    state.job_queue.insert(index, QueuedJob {
+        parameters: job_parameters,
+        submitter,
+        queued_at: now(),
+        content_hash,
+    });
+    state.metrics.jobs_queued += 1;
+    state.save();
+    // jobs of lower priority have moved back
+    notify_queue_positions(state, index + 1)?;
+    let queue_len = state.job_queue.len();
+    dispatch_next_job(state)?;
+    let dispatched = state.job_queue.len() < queue_len;
+    Ok(Ok(index + 1 - dispatched as usize))
+}
+
+/// Notify every open WebSocket channel; a failed push only drops that channel.
+fn push_image_event(state: &mut State, event: &ImageEvent) {
+    let Ok(bytes) = serde_json::to_vec(event) else {
+        return;
+    };
+    let transport = &state.transport;
+    state.ws_channels.retain(|channel_id| transport.push_ws(*channel_id, bytes.clone()).is_ok());
+}
+
+fn image_mime_type(file: &str) -> &'static str {
+    match file.rsplit_once('.').map(|(_, extension)| extension) {
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("webp") => "image/webp",
+        Some("json") => "application/json",
+        _ => "application/octet-stream",
+    }
+}
+
+/// `POST /job` with a JSON `JobParameters` body. Router `job_id`s are assigned
+/// asynchronously, so the response carries the job's queue position instead
+/// (0: already dispatched); progress follows on the updates WebSocket.
+fn handle_http_job_request(
+    request: &http::IncomingHttpRequest,
+    source: &Address,
+    workflows_dir: &str,
+    state: &mut State,
+) -> anyhow::Result<()> {
+    if request.method()? != http::Method::POST {
+        return respond_http(&mut state.effects, http::StatusCode::METHOD_NOT_ALLOWED, None, vec![]);
+    }
+    let Some(bytes) = state.transport.blob() else {
+        return respond_http(&mut state.effects, http::StatusCode::BAD_REQUEST, None, vec![]);
+    };
+    let job_parameters: JobParameters = match serde_json::from_slice(&bytes) {
+        Ok(job_parameters) => job_parameters,
+        Err(e) => {
+            return respond_http(
+                &mut state.effects,
+                http::StatusCode::BAD_REQUEST,
+                None,
+                serde_json::to_vec(&serde_json::json!({ "error": e.to_string() }))?,
+            );
+        }
+    };
+    let json_headers = Some(HashMap::from([(
+        "Content-Type".to_string(),
+        "application/json".to_string(),
+    )]));
+    match submit_job(state, job_parameters, None, source.clone(), workflows_dir)? {
+        Ok(queue_position) => respond_http(
+            &mut state.effects,
+            http::StatusCode::ACCEPTED,
+            json_headers,
+            serde_json::to_vec(&serde_json::json!({ "queue_position": queue_position }))?,
+        ),
+        Err(RunResponse::JobQueued { job_id }) => respond_http(
+            &mut state.effects,
+            http::StatusCode::OK,
+            json_headers,
+            serde_json::to_vec(&serde_json::json!({ "job_id": job_id }))?,
+        ),
+        Err(RunResponse::PaymentRequired) => respond_http(
+            &mut state.effects,
+            http::StatusCode::PAYMENT_REQUIRED,
+            json_headers,
+            serde_json::to_vec(&serde_json::json!({ "error": "payment required" }))?,
+        ),
+        Err(response) => respond_http(
+            &mut state.effects,
+            http::StatusCode::SERVICE_UNAVAILABLE,
+            json_headers,
+            serde_json::to_vec(&serde_json::json!({ "error": format!("{response:?}") }))?,
+        ),
+    }
+}
+
+fn handle_http_request(
+    our: &Address,
+    message: &Message,
+    images_dir: &str,
+    workflows_dir: &str,
+    state: &mut State,
+) -> anyhow::Result<()> {
+    if message.source().node() != our.node()
+        || message.source().process.to_string() != "http_server:distro:sys"
+    {
+        return Err(NotAMatchError::NotAMatch.into());
+    }
+    match serde_json::from_slice::<http::HttpServerRequest>(message.body())? {
+        http::HttpServerRequest::Http(request) => {
+            let bound_path = request.bound_path(Some(&our.process.to_string())).to_string();
+            if bound_path == JOB_HTTP_PATH {
+                return handle_http_job_request(&request, message.source(), workflows_dir, state);
+            }
+            if bound_path == METRICS_HTTP_PATH {
+                return respond_http(
+                    &mut state.effects,
+                    http::StatusCode::OK,
+                    Some(HashMap::from([(
+                        "Content-Type".to_string(),
+                        "text/plain; version=0.0.4".to_string(),
+                    )])),
+                    state.metrics.to_prometheus().into_bytes(),
+                );
+            }
+            let url_params = request.url_params();
+            let Some(file) = url_params.get("file") else {
+                return respond_http(&mut state.effects, http::StatusCode::BAD_REQUEST, None, vec![]);
+            };
+            let job_id = url_params.get("job_id");
+            let is_unsafe = |segment: &String| segment.contains('/') || segment.contains("..");
+            if is_unsafe(file) || job_id.map(is_unsafe).unwrap_or(false) {
+                return respond_http(&mut state.effects, http::StatusCode::BAD_REQUEST, None, vec![]);
+            }
+            let path = match job_id {
+                Some(job_id) => format!("{images_dir}/{job_id}/{file}"),
+                None => format!("{images_dir}/{file}"),
+            };
+            let bytes = match state.transport.read_file(&path) {
+                Ok(bytes) => bytes,
+                Err(_) => {
+                    return respond_http(&mut state.effects, http::StatusCode::NOT_FOUND, None, vec![]);
+                }
+            };
+            respond_http(
+                &mut state.effects,
+                http::StatusCode::OK,
+                Some(HashMap::from([(
+                    "Content-Type".to_string(),
+                    image_mime_type(file).to_string(),
+                )])),
+                bytes,
+            )?;
+        }
+        http::HttpServerRequest::WebSocketOpen { path, channel_id } => {
+            if path == UPDATES_WS_PATH {
+                state.ws_channels.insert(channel_id);
+            }
+        }
+        http::HttpServerRequest::WebSocketClose(channel_id) => {
+            state.ws_channels.remove(&channel_id);
+        }
+        http::HttpServerRequest::WebSocketPush { .. } => {}
+    }
+    Ok(())
+}
+
+/// Handle a `PublicRequest::JobUpdate`, whose image, or chunk of one, is `blob`.
+fn handle_job_update(
+    message: &Message,
+    blob: Option<&[u8]>,
+    images_dir: &str,
+    state: &mut State,
+) -> anyhow::Result<()> {
+    let Ok(PublicRequest::JobUpdate { job_id, is_final, signature, sha256, chunk }) =
+        serde_json::from_slice(message.body())
+    else {
+        return Err(NotAMatchError::NotAMatch.into());
+    };
+    let Some(ref mut current_job) = state.current_job else {
+        log_warn!("unexpectedly got JobUpdate with no current_job set");
+        return Err(JobError::NoCurrentJob { job_id }.into());
+    };
+    let serving_router = state
+        .router_process
+        .clone()
+        .map(|router_process| Address::new(current_job.router.clone(), router_process));
+    if serving_router.as_ref() != Some(message.source()) {
+        let error = JobError::WrongRouter {
+            job_id,
+            sender: message.source().to_string(),
+            served_by: serving_router.map(|router| router.to_string()),
+        };
+        respond(&mut state.effects, &PublicResponse::Error(error.to_string()))?;
+        return Err(error.into());
+    }
+    let is_unknown = !state.on_chain_state.members.contains_key(&current_job.router);
+    if is_unknown && current_job.looked_up_router.as_ref() != Some(&current_job.router) {
+        // the router may have joined since chain state was last fetched
+        let router = current_job.router.clone();
+        current_job.looked_up_router = Some(router.clone());
+        if let Err(e) = fetch_chain_slice(state, ReadRequest::Member(router)) {
+            log_warn!("failed to look up router address: {e:?}");
+        }
+        return handle_job_update(message, blob, images_dir, state);
+    }
+    match current_job.job_id {
+        None => {
+            log_debug!("got JobUpdate for {job_id} before RunResponse::JobQueued");
+            current_job.job_id = Some(job_id);
+            notify_submitter(
+                &mut state.effects,
+                &current_job.submitter,
+                &PublicResponse::RunJob(RunResponse::JobQueued { job_id }),
+            )?;
+            let serve_timeout = serve_timeout_seconds(state);
+            arm_job_timer(state, JobPhase::Serve, serve_timeout)?;
+            return handle_job_update(message, blob, images_dir, state);
+        }
+        Some(current_job_id) if current_job_id != job_id => {
+            return Err(JobError::UnknownJob { job_id, current_job_id }.into());
+        }
+        Some(_) => {}
+    }
+    let signature = match signature {
+        Ok(signature) => signature,
+        Err(reason) => {
+            // the router has given up on the job: no final image is coming
+            let error = format!("router {} failed job {job_id}: {reason}", current_job.router);
+            log_warn!("{error}");
+            notify_submitter(
+                &mut state.effects,
+                &current_job.submitter,
+                &PublicResponse::run_job_error(error.clone()),
+            )?;
+            return finish_current_job(state, JobOutcome::Failed(error));
+        }
+    };
+    let Some(bytes) = blob else {
+        return Err(JobError::MissingBlob { job_id }.into());
+    };
+    let output_format = current_job.parameters.output_format;
+    // a job keeps writing to the drive it started on if the drive is changed
+    let images_dir = current_job.images_dir.get_or_insert_with(|| images_dir.to_string());
+    let job_dir = format!("{images_dir}/{job_id}");
+    let file_stem = format!(
+        "{job_dir}/{}",
+        if is_final { "final".to_string() } else { current_job.next_image_number.to_string() },
+    );
+    let image_path = format!("{file_stem}.{}", output_format.extension());
+    let max_image_bytes = state.on_chain_state.max_image_bytes;
+    let image_sha256 = match chunk {
+        None => {
+            if bytes.len() as u64 > max_image_bytes {
+                return Err(JobError::TooLarge {
+                    job_id,
+                    size: bytes.len() as u64,
+                    limit: max_image_bytes,
+                }
+                .into());
+            }
+            if !output_format.matches(bytes) {
+                return Err(JobError::WrongFormat { job_id, expected: output_format }.into());
+            }
+            hex::encode(Sha256::digest(bytes))
+        }
+        Some(ref chunk) => {
+            let received = receive_chunk(
+                state.transport.as_ref(),
+                current_job,
+                job_id,
+                &job_dir,
+                &image_path,
+                chunk,
+                bytes,
+                max_image_bytes,
+            )?;
+            match received {
+                Some(image_sha256) => image_sha256,
+                // more chunks to come
+                None => return Ok(()),
+            }
+        }
+    };
+    let verified = match sha256 {
+        Some(expected) if !image_sha256.eq_ignore_ascii_case(&expected) => {
+            log_warn!("JobUpdate for {job_id} failed checksum; dropping image");
+            Err(JobError::ChecksumMismatch { job_id, actual: image_sha256, expected })
+        }
+        _ => match state.on_chain_state.members.get(&current_job.router) {
+            None => Err(JobError::UnknownRouter { job_id, router: current_job.router.clone() }),
+            Some(router_key) => {
+                let signed_payload = JobUpdateSignedPayload { job_id, image_sha256, is_final };
+                if signed_payload.verify(&signature, router_key) {
+                    Ok(())
+                } else {
+                    log_warn!("JobUpdate for {job_id} has a bad signature; dropping image");
+                    Err(JobError::BadSignature { job_id, router: current_job.router.clone() })
+                }
+            }
+        },
+    };
+    if let Err(e) = verified {
+        if chunk.is_some() {
+            let _ = state.transport.remove_file(&image_path);
+        }
+        return Err(e.into());
+    }
+    let metadata = ImageMetadata {
+        job_id,
+        image_number: current_job.next_image_number,
+        is_final,
+        written_at: now(),
+        parameters: current_job.parameters.clone(),
+    };
+    // re-encoding replaces the router's image, so only once it is verified
+    let reencoded = match current_job.parameters.jpeg_quality {
+        None => None,
+        Some(quality) => {
+            let original: Cow<[u8]> = if chunk.is_none() {
+                Cow::Borrowed(bytes)
+            } else {
+                Cow::Owned(state.transport.read_file(&image_path)?)
+            };
+            match reencode_jpeg(&original, quality) {
+                Ok(reencoded) => Some(reencoded),
+                Err(e) => {
+                    log_warn!("failed to re-encode image of job {job_id}; saving it as sent: {e:?}");
+                    None
+                }
+            }
+        }
+    };
+    // write before advancing next_image_number: if the write fails the job
+    //  stays current, and the router may resend the image under the same number
+    if metadata.image_number == 0 && chunk.is_none() {
+        state.transport.create_dir(&job_dir).map_err(|e| JobError::WriteFailed {
+            job_id,
+            path: job_dir.clone(),
+            reason: format!("{e:#}"),
+        })?;
+    }
+    let unwritten_bytes = match reencoded {
+        Some(ref reencoded) => Some(reencoded.as_slice()),
+        None if chunk.is_none() => Some(bytes),
+        // chunks were written as they arrived
+        None => None,
+    };
+    let written = write_image(state.transport.as_ref(), &file_stem, output_format, unwritten_bytes, &metadata);
+    written.map_err(|e| JobError::WriteFailed {
+        job_id,
+        path: file_stem.clone(),
+        reason: format!("{e:#}"),
+    })?;
+    let return_final_image = is_final
+        && current_job.parameters.return_final_image
+        && !is_http_submitter(&current_job.submitter);
+    // a chunked image is only read back into memory if it must be sent on or thumbnailed
+    let image_bytes: Cow<[u8]> = if let Some(reencoded) = reencoded {
+        Cow::Owned(reencoded)
+    } else if chunk.is_none() {
+        Cow::Borrowed(bytes)
+    } else if return_final_image || metadata.parameters.thumbnail {
+        Cow::Owned(state.transport.read_file(&image_path)?)
+    } else {
+        Cow::Borrowed(&[])
+    };
+    if return_final_image {
+        state.effects.push(Effect::SendRequest {
+            target: current_job.submitter.clone(),
+            body: serde_json::to_vec(&PublicResponse::FinalImage { job_id, output_format })?,
+            blob: Some(image_bytes.to_vec()),
+        });
+    }
+    if let Some(batch_size) = current_job.parameters.batch_size {
+        let images_before_final = current_job.next_image_number;
+        if is_final && images_before_final != batch_size {
+            log_warn!(
+                "job {job_id} finished after {images_before_final} images, expected batch_size {batch_size}"
+            );
+        } else if !is_final && images_before_final >= batch_size {
+            log_warn!(
+                "job {job_id} sent image {} beyond its batch_size {batch_size}",
+                images_before_final + 1,
+            );
+        }
+    }
+    if metadata.parameters.thumbnail {
+        // a missing thumbnail does not invalidate the image
+        let thumbnail_path = format!("{file_stem}-thumb.jpg");
+        match make_thumbnail(output_format, &image_bytes) {
+            Ok(thumbnail) => state.effects.push(Effect::WriteFile { path: thumbnail_path, bytes: thumbnail }),
+            Err(e) => log_warn!("failed to make {thumbnail_path}: {e:?}"),
+        }
+    }
+    current_job.next_image_number += 1;
+    notify_subscribers(
+        &mut state.effects,
+        current_job,
+        JobEvent::ImageSaved { image_number: metadata.image_number, is_final },
+    )?;
+    if is_final {
+        // done!
+        finish_current_job(state, JobOutcome::Completed)?;
+    } else {
+        state.save();
+    }
+    state.effects.push(Effect::PushImageEvent(ImageEvent {
+        job_id,
+        image_number: metadata.image_number,
+        is_final,
+    }));
+    Ok(())
+}
+
+fn handle_public_request(
+    our: &Address,
+    message: &Message,
+    images_dir: &str,
+    workflows_dir: &str,
+    state: &mut State,
+) -> anyhow::Result<()> {
+    match serde_json::from_slice(message.body()) {
+        Ok(PublicRequest::RunJob(job_parameters)) => {
+            let submitter = message.source().clone();
+            if let Some(reason) = client_rejection(our, state, &submitter) {
+                respond(&mut state.effects, &PublicResponse::run_job_error(reason))?;
+                return Ok(());
+            }
+            let idempotency_key = job_parameters.idempotency_key.clone();
+            let blob = state.transport.blob();
+            let response = match submit_job(state, job_parameters, blob.as_deref(), submitter, workflows_dir)? {
+                Ok(position) => RunResponse::QueuePosition { position, idempotency_key },
+                Err(response) => response,
+            };
+            respond(&mut state.effects, &PublicResponse::RunJob(response))?;
+        }
+        Ok(PublicRequest::ValidateJob(job_parameters)) => {
+            let errors = match client_rejection(our, state, message.source()) {
+                Some(reason) => vec![reason],
+                None => check_job(state, job_parameters, workflows_dir),
+            };
+            respond(&mut state.effects, &PublicResponse::Validation { ok: errors.is_empty(), errors })?;
+        }
+        Ok(PublicRequest::JobUpdate { .. }) => {
+            // read now: looking up the router on chain receives another message,
+            //  whose blob replaces this one's
+            let blob = state.transport.blob();
+            handle_job_update(message, blob.as_deref(), images_dir, state)?;
+        }
+        Ok(PublicRequest::GetJobStatus { job_id }) => {
+            let response = match state.current_job {
+                Some(ref current_job) if current_job.job_id == Some(job_id) => {
+                    PublicResponse::JobStatus {
+                        job_id,
+                        images_received: current_job.next_image_number,
+                        is_complete: false,
+                        queued_at: current_job.queued_at,
+                        seed: current_job.parameters.seed,
+                        workflow: WorkflowSummary::new(
+                            current_job.parameters.name.clone(),
+                            &current_job.parameters.workflow,
+                        ),
+                    }
+                }
+                _ => match state.job_history.iter().rev().find(|r| r.job_id == Some(job_id)) {
+                    Some(record) => PublicResponse::JobStatus {
+                        job_id,
+                        images_received: record.image_count,
+                        is_complete: true,
+                        queued_at: record.queued_at,
+                        seed: record.seed,
+                        workflow: JobSummary::from(record).workflow,
+                    },
+                    None => PublicResponse::UnknownJob { job_id },
+                },
+            };
+            respond(&mut state.effects, &response)?;
+        }
+        Ok(PublicRequest::Subscribe { job_id }) => {
+            let subscriber = message.source().clone();
+            let response = match state.current_job {
+                Some(ref mut current_job) if current_job.job_id == Some(job_id) => {
+                    if current_job.subscribers.contains(&subscriber) {
+                        PublicResponse::Subscribed { job_id }
+                    } else if current_job.subscribers.len() >= MAX_SUBSCRIBERS_PER_JOB {
+                        PublicResponse::Error(format!(
+                            "job {job_id} already has {MAX_SUBSCRIBERS_PER_JOB} subscribers"
+                        ))
+                    } else {
+                        log_debug!("{subscriber} subscribed to job {job_id}");
+                        current_job.subscribers.push(subscriber);
+                        PublicResponse::Subscribed { job_id }
+                    }
+                }
+                _ => match state.job_history.iter().rev().find(|r| r.job_id == Some(job_id)) {
+                    Some(record) => PublicResponse::JobEvent {
+                        job_id,
+                        event: JobEvent::Finished {
+                            outcome: record.outcome.clone(),
+                            images_received: record.image_count,
+                        },
+                    },
+                    None => PublicResponse::UnknownJob { job_id },
+                },
+            };
+            respond(&mut state.effects, &response)?;
+        }
+        Ok(PublicRequest::GetHistory { limit }) => {
+            let history = state
+                .job_history
+                .iter()
+                .rev()
+                .take(limit)
+                .map(JobSummary::from)
+                .collect();
+            respond(&mut state.effects, &PublicResponse::History(history))?;
+        }
+        Ok(PublicRequest::Ping) => {
+            let pong = PublicResponse::Pong {
+                queue_depth: state.job_queue.len(),
+                has_router: state.router_process.is_some() && !state.on_chain_state.routers.is_empty(),
+                has_sequencer: !state.rollup_sequencers.is_empty(),
+                version: env!("CARGO_PKG_VERSION").to_string(),
+            };
+            respond(&mut state.effects, &pong)?;
+        }
+        Ok(PublicRequest::ListActiveJobs) => {
+            // submitters are only shown to our own node, as to an admin
+            let show_submitter = message.source().node() == our.node();
+            let running = state.current_job.iter().map(|current_job| ActiveJob {
+                job_id: current_job.job_id,
+                queue_position: 0,
+                submitter: show_submitter.then(|| current_job.submitter.to_string()),
+                workflow: WorkflowSummary::new(current_job.parameters.name.clone(), &current_job.parameters.workflow),
+                priority: current_job.parameters.priority,
+                images_received: current_job.next_image_number,
+                queued_at: current_job.queued_at,
+            });
+            let queued = state.job_queue.iter().enumerate().map(|(index, queued_job)| ActiveJob {
+                job_id: None,
+                queue_position: index + 1,
+                submitter: show_submitter.then(|| queued_job.submitter.to_string()),
+                workflow: WorkflowSummary::new(queued_job.parameters.name.clone(), &queued_job.parameters.workflow),
+                priority: queued_job.parameters.priority,
+                images_received: 0,
+                queued_at: queued_job.queued_at,
+            });
+            let active_jobs = running.chain(queued).collect();
+            respond(&mut state.effects, &PublicResponse::ActiveJobs(active_jobs))?;
+        }
+        Ok(PublicRequest::GetMetrics) => {
+            respond(&mut state.effects, &PublicResponse::Metrics(state.metrics.clone()))?;
+        }
+        Ok(PublicRequest::CancelJob { job_id }) => {
+            let was_running = state
+                .current_job
+                .as_ref()
+                .map(|current_job| current_job.job_id == Some(job_id))
+                .unwrap_or(false);
+            if was_running {
+                if let Some(router) = current_router_address(state) {
+                    state.effects.push(Effect::SendToRouter {
+                        router,
+                        request: PublicRequest::CancelJob { job_id },
+                        blob: None,
+                        expects_response: None,
+                    });
+                }
+                log_info!("cancelled job {job_id}");
+            }
+            respond(&mut state.effects, &PublicResponse::JobCancelled { job_id, was_running })?;
+            if was_running {
+                finish_current_job(state, JobOutcome::Cancelled)?;
+            }
+        }
+        Err(_e) => {
+            return Err(NotAMatchError::NotAMatch.into());
+        }
+    }
+    Ok(())
+}
+
+/// Whether `message`, a router's answer to `RunJob`, answers the attempt
+/// `current_job` is waiting on: it must come from the job's router and carry
+/// the timer generation that attempt was sent with. Answers to an attempt
+/// since failed over from, or retried, are stale.
+fn answers_current_attempt(message: &Message, current_job: &CurrentJob) -> bool {
+    let generation = message.context().and_then(|context| serde_json::from_slice::<u64>(context).ok());
+    message.source().node() == current_job.router && generation == Some(current_job.timer_generation)
+}
+
+fn handle_public_response(
+    message: &Message,
+    state: &mut State,
+) -> anyhow::Result<()> {
+    match serde_json::from_slice(message.body()) {
+        Ok(PublicResponse::RunJob(response)) => {
+            let is_current = state
+                .current_job
+                .as_ref()
+                .map(|current_job| answers_current_attempt(message, current_job))
+                .unwrap_or(false);
+            if !is_current {
+                log_debug!("ignoring stale {response:?} from {}", message.source());
+                return Ok(());
+            }
+            match response {
+                RunResponse::JobQueued { job_id } => {
+                    if state.job_history.iter().any(|record| record.job_id == Some(job_id)) {
+                        // final JobUpdate beat the JobQueued: job is already done
+                        log_debug!("got RunResponse::JobQueued for finished job {job_id}");
+                        return Ok(());
+                    }
+                    let Some(ref mut current_job) = state.current_job else {
+                        return Err(anyhow::anyhow!("got RunResponse::JobQueued for {job_id} with no current_job"));
+                    };
+                    match current_job.job_id {
+                        Some(current_job_id) if current_job_id != job_id => {
+                            return Err(anyhow::anyhow!(
+                                "got RunResponse::JobQueued for {job_id} but current_job is {current_job_id}"
+                            ));
+                        }
+                        Some(_) => {
+                            // JobUpdates arrived first and already armed the serve timer
+                            log_debug!("get RunResponse::JobQueued for {job_id} after its first JobUpdate");
+                            return Ok(());
+                        }
+                        None => {}
+                    }
+                    current_job.job_id = Some(job_id);
+                    state.unhealthy_routers.remove(&current_job.router);
+                    notify_submitter(
+                        &mut state.effects,
+                        &current_job.submitter,
+                        &PublicResponse::RunJob(RunResponse::JobQueued { job_id }),
+                    )?;
+                    let serve_timeout = serve_timeout_seconds(state);
+                    arm_job_timer(state, JobPhase::Serve, serve_timeout)?;
+                    state.save();
+                    log_debug!("get RunResponse::JobQueued for {job_id}");
+                }
+                RunResponse::PaymentRequired => {
+                    log_info!("got RunResponse::PaymentRequired");
+                    state.outstanding_payments = state.outstanding_payments.saturating_add(1);
+                    if let Some(ref current_job) = state.current_job {
+                        notify_submitter(
+                            &mut state.effects,
+                            &current_job.submitter,
+                            &PublicResponse::RunJob(RunResponse::PaymentRequired),
+                        )?;
+                    }
+                    finish_current_job(state, JobOutcome::Failed("payment required".to_string()))?;
+                }
+                RunResponse::Error(e) => {
+                    let error = format!("router {} rejected job: {e}", message.source().node());
+                    log_warn!("{error}");
+                    if let Some(ref current_job) = state.current_job {
+                        notify_submitter(
+                            &mut state.effects,
+                            &current_job.submitter,
+                            &PublicResponse::run_job_error(error.clone()),
+                        )?;
+                    }
+                    finish_current_job(state, JobOutcome::Failed(error))?;
+                }
+                RunResponse::QueuePosition { position, .. } => {
+                    // positions are ours to give submitters; a router has no business sending one
+                    log_debug!("ignoring RunResponse::QueuePosition {position} from {}", message.source());
+                }
+            }
+        }
+        Ok(PublicResponse::JobUpdate) => {}
+        Ok(_) | Err(_) => {
+            return Err(NotAMatchError::NotAMatch.into());
+        }
+    }
+    Ok(())
+}
+
+/// The sequencer is a Kinode process, e.g. `node.os@sequencer:provider-dao-rollup:nick1udwig.os`,
+/// not the Ethereum address of a DAO member.
+fn parse_sequencer_address(address: &str) -> Result<Address, String> {
+    let address = address.trim();
+    if AlloyAddress::from_str(address).is_ok() {
+        return Err(format!(
+            "{address} is an Ethereum address; expected the Kinode address of the sequencer process, e.g. node.os@sequencer:provider-dao-rollup:nick1udwig.os"
+        ));
+    }
+    let Some((node, process)) = address.split_once('@') else {
+        return Err(format!("{address} is missing `@`; expected node@process:package:publisher"));
+    };
+    if node.is_empty() {
+        return Err(format!("{address} is missing a node before `@`"));
+    }
+    if process.split(':').count() != 3 {
+        return Err(format!("{address} has malformed process id {process}; expected process:package:publisher"));
+    }
+    address
+        .parse()
+        .map_err(|e| format!("invalid sequencer address {address}: {e:?}"))
+}
+
+/// Every `AdminRequest` gets exactly one `AdminResponse`: errors the operator
+/// can act on are reported in it, and `Ok(())` returned. An `Err` is returned
+/// only for unexpected failures, e.g. of saving state, and is logged by `init`.
+fn handle_admin_request(
+    our: &Address,
+    message: &Message,
+    workflows_dir: &str,
+    state: &mut State,
+) -> anyhow::Result<()> {
+    let source = message.source();
+    if source.node() != our.node() {
+        if serde_json::from_slice::<AdminRequest>(message.body()).is_err() {
+            return Err(NotAMatchError::NotAMatch.into());
+        }
+        log_warn!("only our node can make AdminRequests; rejecting from {source}");
+        let err = format!("only {} can make AdminRequests", our.node());
+        respond(&mut state.effects, &AdminResponse::Error(err))?;
+        return Ok(());
+    }
+    match serde_json::from_slice(message.body()) {
+        Ok(AdminRequest::SetRouterProcess { process_id }) => {
+            let process_id = match process_id.parse::<ProcessId>() {
+                Ok(process_id) => process_id,
+                Err(e) => {
+                    let err = format!(
+                        "invalid process id {process_id:?}: {e}; expected process:package:publisher"
+                    );
+                    respond(&mut state.effects, &AdminResponse::SetRouterProcess { err: Some(err) })?;
+                    return Ok(());
+                }
+            };
+            state.router_process = Some(process_id);
+            state.save();
+            respond(&mut state.effects, &AdminResponse::SetRouterProcess { err: None })?;
+        }
+        Ok(AdminRequest::SetRollupSequencer { address }) => {
+            let address = match parse_sequencer_address(&address) {
+                Ok(address) => address,
+                Err(err) => {
+                    respond(&mut state.effects, &AdminResponse::SetRollupSequencer { err: Some(err) })?;
+                    return Ok(());
+                }
+            };
+            state.rollup_sequencers = vec![address];
+            state.active_sequencer = None;
+            state.save();
+            let err = await_chain_state(state).err().map(|e| e.to_string());
+            respond(&mut state.effects, &AdminResponse::SetRollupSequencer { err })?;
+        }
+        Ok(AdminRequest::SetRouterHealthCheck { interval_seconds, remove_unhealthy_after_seconds }) => {
+            state.router_health_interval_seconds = interval_seconds;
+            state.remove_unhealthy_routers_after_seconds = remove_unhealthy_after_seconds;
+            state.save();
+            arm_router_health_timer(state)?;
+            respond(&mut state.effects, &AdminResponse::SetRouterHealthCheck { err: None })?;
+        }
+        Ok(AdminRequest::SetImagesDrive { drive }) => {
+            let is_valid = !drive.is_empty()
+                && drive.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+            let err = if !is_valid {
+                Some(format!("invalid drive name {drive:?}: use only letters, digits, `-`, and `_`"))
+            } else if [WORKFLOWS_DRIVE, STATE_DRIVE, INPUTS_DRIVE].contains(&drive.as_str()) {
+                Some(format!("drive {drive} is reserved"))
+            } else {
+                // the drive is opened by the main loop once this message is handled
+                state.images_drive = drive;
+                state.save();
+                None
+            };
+            respond(&mut state.effects, &AdminResponse::SetImagesDrive { err })?;
+        }
+        Ok(AdminRequest::ClearQueue) => {
+            let removed = state.job_queue.len();
+            let cleared: Vec<QueuedJob> = state.job_queue.drain(..).collect();
+            for queued_job in cleared {
+                release_input_images(state, &queued_job.parameters.input_images);
+                notify_submitter(
+                    &mut state.effects,
+                    &queued_job.submitter,
+                    &PublicResponse::run_job_error("job cleared from queue by operator"),
+                )?;
+            }
+            state.metrics.jobs_cancelled += removed as u64;
+            state.save();
+            log_info!("cleared {removed} queued jobs");
+            respond(&mut state.effects, &AdminResponse::ClearQueue { removed })?;
+        }
+        Ok(AdminRequest::ClearCurrentJob) => {
+            let removed = match state.current_job {
+                None => 0,
+                Some(ref current_job) => {
+                    if let (Some(job_id), Some(router)) = (current_job.job_id, current_router_address(state)) {
+                        state.effects.push(Effect::SendToRouter {
+                            router,
+                            request: PublicRequest::CancelJob { job_id },
+                            blob: None,
+                            expects_response: None,
+                        });
+                    }
+                    notify_submitter(
+                        &mut state.effects,
+                        &current_job.submitter,
+                        &PublicResponse::run_job_error("job cleared by operator"),
+                    )?;
+                    log_info!("cleared current job {:?}", current_job.job_id);
+                    1
+                }
+            };
+            respond(&mut state.effects, &AdminResponse::ClearCurrentJob { removed })?;
+            if removed > 0 {
+                finish_current_job(state, JobOutcome::Cancelled)?;
+            }
+        }
+        Ok(AdminRequest::GetConfig) => {
+            let config = state.config();
+            respond(&mut state.effects, &AdminResponse::GetConfig(config))?;
+        }
+        Ok(AdminRequest::GetRouterHealth) => {
+            let health = state
+                .on_chain_state
+                .routers
+                .iter()
+                .map(|router| {
+                    let health = state.router_health.get(router).cloned().unwrap_or_default();
+                    RouterHealthSummary {
+                        router: router.clone(),
+                        healthy: !state.unhealthy_routers.contains(router),
+                        last_seen: health.last_seen,
+                        unhealthy_since: health.unhealthy_since,
+                    }
+                })
+                .collect();
+            respond(&mut state.effects, &AdminResponse::GetRouterHealth(health))?;
+        }
+        Ok(AdminRequest::SetRollupSequencers { addresses }) => {
+            let addresses: Result<Vec<Address>, String> =
+                addresses.iter().map(|address| parse_sequencer_address(address)).collect();
+            let addresses = match addresses {
+                Ok(addresses) if addresses.is_empty() => Err("no sequencer addresses given".to_string()),
+                addresses => addresses,
+            };
+            let err = match addresses {
+                Ok(addresses) => {
+                    state.rollup_sequencers = addresses;
+                    state.active_sequencer = None;
+                    state.save();
+                    await_chain_state(state).err().map(|e| e.to_string())
+                }
+                Err(err) => Some(err),
+            };
+            respond(&mut state.effects, &AdminResponse::SetRollupSequencers { err })?;
+        }
+        Ok(AdminRequest::GetRollupState { force }) => {
+            let fetched = if state.rollup_sequencers.is_empty() {
+                Err(anyhow::anyhow!("no rollup sequencer set"))
+            } else if force {
+                await_chain_state(state)
+            } else {
+                refresh_chain_state(state)
+            };
+            let response = match fetched {
+                Ok(()) => AdminResponse::GetRollupState { fetched_at: state.last_chain_fetch, err: None },
+                // the cached state is still served, so say how old it is
+                Err(e) => AdminResponse::GetRollupState { fetched_at: state.last_chain_fetch, err: Some(e.to_string()) },
+            };
+            respond(&mut state.effects, &response)?;
+        }
+        Ok(AdminRequest::SetSigningKey { key }) => {
+            let err = match key.parse::<LocalWallet>() {
+                Ok(_) => {
+                    state.signing_key = Some(key);
+                    state.save();
+                    None
+                }
+                Err(e) => Some(format!("invalid signing key: {e}")),
+            };
+            respond(&mut state.effects, &AdminResponse::SetSigningKey { err })?;
+        }
+        Ok(AdminRequest::CreateProposal { proposal }) => {
+            // membership is checked before signing: make sure it is current
+            if let Err(e) = fetch_chain_slice(state, ReadRequest::Member(our.node().to_string())) {
+                log_warn!("failed to refresh our membership; using cached: {e:?}");
+            }
+            let proposal_hash = hash_proposal(&proposal);
+            let response = match check_proposal(&proposal)
+                .and_then(|_| sign_transaction(our, state, Transaction::Propose(proposal)))
+                .and_then(|transaction| submit_transaction(state, transaction))
+            {
+                Ok(tx_hash) => AdminResponse::CreateProposal {
+                    proposal_hash: Some(proposal_hash),
+                    tx_hash: Some(tx_hash),
+                    err: None,
+                },
+                Err(e) => AdminResponse::CreateProposal {
+                    proposal_hash: None,
+                    tx_hash: None,
+                    err: Some(e.to_string()),
+                },
+            };
+            respond(&mut state.effects, &response)?;
+        }
+        Ok(AdminRequest::Vote { proposal_hash, is_yea }) => {
+            if let Err(e) = fetch_chain_slice(state, ReadRequest::Proposals) {
+                log_warn!("failed to refresh proposals; using cached: {e:?}");
+            }
+            let existing_vote = state
+                .on_chain_state
+                .proposals
+                .get(&proposal_hash)
+                .and_then(|proposal| proposal.votes.get(our.node()));
+            let err = if !state.on_chain_state.proposals.contains_key(&proposal_hash) {
+                Some(format!("no proposal with hash {proposal_hash}"))
+            } else if let Some(existing_vote) = existing_vote {
+                Some(format!(
+                    "already voted {} on proposal {proposal_hash}; votes cannot be changed",
+                    if existing_vote.vote.is_yea { "yea" } else { "nay" },
+                ))
+            } else {
+                sign_vote(our, state, Vote { proposal_hash, is_yea })
+                    .and_then(|signed_vote| sign_transaction(our, state, Transaction::Vote(signed_vote)))
+                    .and_then(|transaction| submit_transaction(state, transaction))
+                    .err()
+                    .map(|e| e.to_string())
+            };
+            respond(&mut state.effects, &AdminResponse::Vote { err })?;
+        }
+        Ok(AdminRequest::GetProposals) => {
+            if let Err(e) = fetch_chain_slice(state, ReadRequest::Proposals) {
+                log_warn!("failed to refresh proposals; using cached: {e:?}");
+            }
+            let dao = &state.on_chain_state;
+            let proposals = state
+                .on_chain_state
+                .proposals
+                .iter()
+                .map(|(proposal_hash, proposal)| ProposalSummary {
+                    proposal_hash: *proposal_hash,
+                    proposal: proposal.proposal.clone(),
+                    tally: tally(proposal, &dao.members, &dao.member_blacklist, &dao.stakes),
+                    expires_in_seconds: proposal.expires_at.saturating_sub(now()),
+                })
+                .collect();
+            respond(&mut state.effects, &AdminResponse::GetProposals(proposals))?;
+        }
+        Ok(AdminRequest::SetChainRefreshInterval { seconds, jitter_percent }) => {
+            if jitter_percent.map(|percent| percent > 100).unwrap_or(false) {
+                let err = "jitter_percent must be at most 100".to_string();
+                respond(&mut state.effects, &AdminResponse::SetChainRefreshInterval { err: Some(err) })?;
+                return Ok(());
+            }
+            state.chain_refresh_interval_seconds = seconds;
+            if let Some(jitter_percent) = jitter_percent {
+                state.chain_refresh_jitter_percent = jitter_percent;
+            }
+            state.save();
+            arm_chain_refresh_timer(state)?;
+            respond(&mut state.effects, &AdminResponse::SetChainRefreshInterval { err: None })?;
+        }
+        Ok(AdminRequest::SaveWorkflow { name, json }) => {
+            let err = match serde_json::from_str::<serde_json::Value>(&json) {
+                Err(e) => Some(format!("workflow is not valid JSON: {e}")),
+                Ok(_) => workflow_path(workflows_dir, &name)
+                    .and_then(|path| state.transport.write_file(&path, json.as_bytes()))
+                    .err()
+                    .map(|e| e.to_string()),
+            };
+            respond(&mut state.effects, &AdminResponse::SaveWorkflow { err })?;
+        }
+        Ok(AdminRequest::ListWorkflows) => {
+            let entries = match state.transport.read_dir(workflows_dir) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    respond(&mut state.effects, &AdminResponse::Error(format!("failed to list workflows: {e}")))?;
+                    return Ok(());
+                }
+            };
+            let mut names: Vec<String> = entries
+                .into_iter()
+                .filter_map(|entry| {
+                    let file_name = entry.path.rsplit('/').next()?;
+                    file_name.strip_suffix(".json").map(|name| name.to_string())
+                })
+                .collect();
+            names.sort();
+            respond(&mut state.effects, &AdminResponse::ListWorkflows(names))?;
+        }
+        Ok(AdminRequest::DeleteWorkflow { name }) => {
+            let err = workflow_path(workflows_dir, &name)
+                .and_then(|path| state.transport.remove_file(&path))
+                .err()
+                .map(|e| e.to_string());
+            respond(&mut state.effects, &AdminResponse::DeleteWorkflow { err })?;
+        }
+        Ok(AdminRequest::SetRetentionPolicy { max_jobs_retained, max_age_hours, dry_run }) => {
+            state.retention_policy = RetentionPolicy { max_jobs_retained, max_age_hours, dry_run };
+            state.save();
+            // an already-armed timer applies the new policy when it fires
+            arm_image_gc_timer(state)?;
+            respond(&mut state.effects, &AdminResponse::SetRetentionPolicy { err: None })?;
+        }
+        Ok(AdminRequest::SetAllowedClients { nodes }) => {
+            state.allowed_clients = nodes;
+            state.save();
+            respond(&mut state.effects, &AdminResponse::SetAllowedClients { err: None })?;
+        }
+        Ok(AdminRequest::ReplayJob { job_id }) => {
+            let record = state.job_history.iter().find(|record| record.job_id == Some(job_id));
+            let response = match record {
+                None => AdminResponse::ReplayJob {
+                    queue_position: None,
+                    job_id: None,
+                    err: Some(format!("job {job_id} is not in the history")),
+                },
+                Some(JobRecord { job_parameters: None, .. }) => AdminResponse::ReplayJob {
+                    queue_position: None,
+                    job_id: None,
+                    err: Some(format!("job {job_id} finished before its parameters were recorded")),
+                },
+                Some(JobRecord { job_parameters: Some(job_parameters), .. })
+                    if !job_parameters.input_images.is_empty() =>
+                {
+                    AdminResponse::ReplayJob {
+                        queue_position: None,
+                        job_id: None,
+                        err: Some(format!("job {job_id} took input images, which are not kept once it finishes")),
+                    }
+                }
+                Some(JobRecord { workflow, job_parameters: Some(job_parameters), .. }) => {
+                    let job_parameters = JobParameters {
+                        workflow: workflow.clone(),
+                        idempotency_key: None,
+                        ..job_parameters.clone()
+                    };
+                    log_info!("replaying job {job_id}");
+                    match submit_job(state, job_parameters, None, source.clone(), workflows_dir)? {
+                        Ok(queue_position) => AdminResponse::ReplayJob {
+                            queue_position: Some(queue_position),
+                            job_id: None,
+                            err: None,
+                        },
+                        Err(RunResponse::JobQueued { job_id }) => AdminResponse::ReplayJob {
+                            queue_position: None,
+                            job_id: Some(job_id),
+                            err: None,
+                        },
+                        Err(response) => AdminResponse::ReplayJob {
+                            queue_position: None,
+                            job_id: None,
+                            err: Some(format!("{response:?}")),
+                        },
+                    }
+                }
+            };
+            respond(&mut state.effects, &response)?;
+        }
+        Ok(AdminRequest::Shutdown) => {
+            cancel_timers(state);
+            state.shutting_down = true;
+            let err = state.persist().err().map(|e| e.to_string());
+            respond(&mut state.effects, &AdminResponse::Shutdown { err })?;
+        }
+        Ok(AdminRequest::SetClientQuota { max_jobs_per_client }) => {
+            let err = if max_jobs_per_client == Some(0) {
+                Some("max_jobs_per_client must be at least 1; use null for no limit".to_string())
+            } else {
+                state.max_jobs_per_client = max_jobs_per_client;
+                state.save();
+                None
+            };
+            respond(&mut state.effects, &AdminResponse::SetClientQuota { err })?;
+        }
+        Ok(AdminRequest::SetParameters {
+            queue_response_timeout_seconds,
+            serve_timeout_seconds,
+            max_outstanding_payments,
+            payment_period_hours,
+        }) => {
+            state.local_parameters = LocalParameters {
+                queue_response_timeout_seconds,
+                serve_timeout_seconds,
+                max_outstanding_payments,
+                payment_period_hours,
+            };
+            log_info!("local parameters in effect: {:?}", state.overridden_parameters());
+            state.save();
+            // takes effect from the next timers armed
+            arm_payment_timer(state)?;
+            respond(&mut state.effects, &AdminResponse::SetParameters { err: None })?;
+        }
+        Ok(AdminRequest::SetLogLevel { level }) => {
+            state.log_level = level;
+            level.set();
+            state.save();
+            respond(&mut state.effects, &AdminResponse::SetLogLevel { err: None })?;
+        }
+        Ok(AdminRequest::SubmitTransaction { transaction }) => {
+            let response = match submit_transaction(state, transaction) {
+                Ok(tx_hash) => AdminResponse::SubmitTransaction { tx_hash: Some(tx_hash), err: None },
+                Err(e) => AdminResponse::SubmitTransaction { tx_hash: None, err: Some(e.to_string()) },
+            };
+            respond(&mut state.effects, &response)?;
+        }
+        Err(e) => {
+            return Err(NotAMatchError::NotAMatch.into());
+        }
+    }
+    Ok(())
+}
+
+fn handle_message(
+    our: &Address,
+    message: &Message,
+    images_dir: &str,
+    workflows_dir: &str,
+    state: &mut State,
+) -> anyhow::Result<()> {
+    if message.is_request() {
+        match handle_http_request(our, message, images_dir, workflows_dir, state) {
+            Ok(_) => return Ok(()),
+            Err(e) => {
+                if e.downcast_ref::<NotAMatchError>().is_none() {
+                    return Err(e);
+                }
+            }
+        }
+        match handle_admin_request(our, message, workflows_dir, state) {
+            Ok(_) => return Ok(()),
+            Err(e) => {
+                if e.downcast_ref::<NotAMatchError>().is_none() {
+                    return Err(e);
+                }
+            }
+        }
+        match handle_public_request(our, message, images_dir, workflows_dir, state) {
+            Ok(_) => return Ok(()),
+            Err(e) => {
+                if e.downcast_ref::<NotAMatchError>().is_none() {
+                    return Err(e);
+                }
+            }
+        }
+        return Err(NotAMatchError::UnexpectedRequest {
+            sender: message.source().to_string(),
+            body: String::from_utf8_lossy(message.body()).into_owned(),
+        }
+        .into());
+    }
+    if let Some(RouterPing { router }) = message
+        .context()
+        .and_then(|context| serde_json::from_slice(context).ok())
+    {
+        // any response, even an error, means the router is up
+        record_router_health(state, &router, true);
+        return Ok(());
+    }
+    match handle_public_response(message, state) {
+        Ok(_) => return Ok(()),
+        Err(e) => {
+            if e.downcast_ref::<NotAMatchError>().is_none() {
+                return Err(e);
+            }
+        }
+    }
+    if message.source().to_string() == format!("{}@timer:distro:sys", our.node()) {
+        let context = message.context().unwrap_or_default();
+        let Ok(TimerContext { kind, epoch }) = serde_json::from_slice::<TimerContext>(context) else {
+            // e.g. set by an older version of the client
+            log_debug!("ignoring unrecognized timer");
+            return Ok(());
+        };
+        if epoch != state.timer_epoch {
+            log_debug!("ignoring {kind:?} timer set before the last restart or shutdown");
+            return Ok(());
+        }
+        let job_timer = match kind {
+            TimerKind::PaymentReset => {
+                state.payment_timer_armed = false;
+                reset_payment_period_if_elapsed(state);
+                state.save();
+                return arm_payment_timer(state);
+            }
+            TimerKind::ChainRefresh => {
+                state.chain_refresh_timer_armed = false;
+                if let Err(e) = refresh_chain_state(state) {
+                    log_warn!("periodic chain state refresh failed: {e:?}");
+                    // still sweep the proposals we have
+                    expire_proposals(state);
+                    state.save();
+                }
+                // no-op if refresh_chain_state fetched and already re-armed
+                return arm_chain_refresh_timer(state);
+            }
+            TimerKind::RouterHealth => {
+                state.router_health_timer_armed = false;
+                if let Err(e) = ping_routers(state) {
+                    log_warn!("failed to ping routers: {e:?}");
+                }
+                if let Err(e) = propose_removing_unhealthy_routers(our, state) {
+                    log_warn!("failed to propose removing unhealthy routers: {e:?}");
+                }
+                state.save();
+                return arm_router_health_timer(state);
+            }
+            TimerKind::ImageGc => {
+                state.image_gc_timer_armed = false;
+                if let Err(e) = collect_garbage(images_dir, state) {
+                    log_error!("image gc failed: {e:?}");
+                }
+                return arm_image_gc_timer(state);
+            }
+            TimerKind::Job(job_timer) => job_timer,
+        };
+        let Some(ref current_job) = state.current_job else {
+            // job already finished
+            return Ok(());
+        };
+        if !job_timer.is_current(current_job) {
+            log_debug!("ignoring stale timer {job_timer:?}");
+            return Ok(());
+        }
+        match job_timer.phase {
+            JobPhase::Retry => return send_current_job(state),
+            JobPhase::Queue => {
+                return fail_over_current_job(state, "timed out waiting for RunResponse::JobQueued");
+            }
+            JobPhase::Serve => {}
+        }
+        notify_submitter(
+            &mut state.effects,
+            &current_job.submitter,
+            &PublicResponse::run_job_error(format!(
+                "job {:?} timed out while being served by {}",
+                job_timer.job_id,
+                current_job.router,
+            )),
+        )?;
+        finish_current_job(state, JobOutcome::TimedOut)?;
+        return Err(anyhow::anyhow!("job {:?} timed out while being served", job_timer.job_id));
+    }
+    Ok(())
+}
+
+/// A failed send of `current_job` to its router is retried with exponential
+/// backoff; once retries are exhausted the job fails over to the next router.
+/// Other `SendError`s leave the queue untouched.
+fn handle_send_error(send_err: &SendError, state: &mut State) -> anyhow::Result<()> {
+    if let Some(RouterPing { router }) = send_err
+        .context()
+        .and_then(|context| serde_json::from_slice(context).ok())
+    {
+        // delivered, but unanswered: see `ping_routers`
+        let is_healthy = matches!(send_err.kind(), SendErrorKind::Timeout);
+        record_router_health(state, &router, is_healthy);
+        state.save();
+        return Ok(());
+    }
+    if state.router_process.as_ref() != Some(&send_err.target().process) {
+        return Ok(());
+    }
+    let Some(generation) = send_err
+        .context()
+        .and_then(|context| serde_json::from_slice::<u64>(context).ok())
+    else {
+        return Ok(());
+    };
+    let Some(ref mut current_job) = state.current_job else {
+        return Ok(());
+    };
+    if current_job.timer_generation != generation {
+        // failed attempt has already been superseded
+        return Ok(());
+    }
+    if current_job.retries >= MAX_DISPATCH_RETRIES {
+        let reason = format!(
+            "unreachable after {} attempts: {:?}",
+            current_job.retries + 1,
+            send_err.kind(),
+        );
+        return fail_over_current_job(state, &reason);
+    }
+    current_job.retries += 1;
+    let delay_seconds = DISPATCH_RETRY_BASE_SECONDS << (current_job.retries - 1);
+    current_job.next_attempt_at = Some(now() + delay_seconds);
+    log_warn!("send to router failed; retry {} in {delay_seconds}s", current_job.retries);
+    arm_job_timer(state, JobPhase::Retry, delay_seconds)?;
+    state.save();
+    Ok(())
+}
+
+call_init!(init);
+fn init(our: Address) {
+    log_info!("{}: begin", our.process());
+
+    let workflows_dir = vfs::create_drive(our.package_id(), WORKFLOWS_DRIVE, None).unwrap();
+    let state_dir = vfs::create_drive(our.package_id(), STATE_DRIVE, None).unwrap();
+    let mut state = State::load(default_transport(), &state_dir);
+    state.inputs_dir = Some(vfs::create_drive(our.package_id(), INPUTS_DRIVE, None).unwrap());
+    state.log_level.set();
+    // timers set by the previous run may still fire
+    cancel_timers(&mut state);
+    if let Err(e) = state.persist() {
+        log_error!("{}: failed to save state: {e:?}", our.process());
+    }
+    let mut images_drive = state.images_drive.clone();
+    let mut images_dir = vfs::create_drive(our.package_id(), &images_drive, None).unwrap();
+    http::bind_http_path(IMAGES_HTTP_PATH, true, false).unwrap();
+    http::bind_http_path(LEGACY_IMAGES_HTTP_PATH, true, false).unwrap();
+    http::bind_http_path(JOB_HTTP_PATH, true, false).unwrap();
+    // unauthenticated so that scrapers need no login, so only from localhost
+    http::bind_http_path(METRICS_HTTP_PATH, false, true).unwrap();
+    http::bind_ws_path(UPDATES_WS_PATH, true, false).unwrap();
+    if let Err(e) = arm_payment_timer(&mut state) {
+        log_error!("{}: failed to arm payment timer: {e:?}", our.process());
+    }
+    if let Err(e) = arm_chain_refresh_timer(&mut state) {
+        log_error!("{}: failed to arm chain refresh timer: {e:?}", our.process());
+    }
+    if let Err(e) = arm_router_health_timer(&mut state) {
+        log_error!("{}: failed to arm router health timer: {e:?}", our.process());
+    }
+    if let Err(e) = arm_image_gc_timer(&mut state) {
+        log_error!("{}: failed to arm image gc timer: {e:?}", our.process());
+    }
+    if let Err(e) = resume_jobs(&mut state) {
+        log_error!("{}: failed to resume jobs: {e:?}", our.process());
+    }
+    run_effects(&mut state);
+
+    loop {
+        let message = match await_message() {
+            Ok(m) => m,
+            Err(send_err) => {
+                log_debug!("SendError");
+                if let Err(e) = handle_send_error(&send_err, &mut state) {
+                    log_error!("{}: error: {:?}", our.process(), e);
+                }
+                run_effects(&mut state);
+                continue;
+            },
+        };
+        match handle_message(
+            &our,
+            &message,
+            &images_dir,
+            &workflows_dir,
+            &mut state,
+        ) {
+            Ok(()) => {}
+            Err(e) => match e.downcast_ref::<NotAMatchError>() {
+                Some(not_a_match) => log_debug!("{}: ignoring {not_a_match}", our.process()),
+                None => log_error!("{}: error: {:?}", our.process(), e),
+            },
+        };
+        // also those queued before a handler failed: the state changes that
+        //  queued them are not rolled back
+        run_effects(&mut state);
+        if state.images_drive != images_drive {
+            match vfs::create_drive(our.package_id(), &state.images_drive, None) {
+                Ok(new_images_dir) => {
+                    log_info!("writing new images to {new_images_dir}");
+                    images_drive = state.images_drive.clone();
+                    images_dir = new_images_dir;
+                }
+                Err(e) => log_error!("failed to open images drive {}: {e:?}", state.images_drive),
+            }
+        }
+        if state.shutting_down {
+            log_info!("{}: shutting down", our.process());
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::BTreeMap;
+    use std::rc::Rc;
+
+    use serde::de::DeserializeOwned;
+
+    use super::*;
+
+    const OUR: &str = "our.os@client:comfyui_client:nick1udwig.os";
+    const SUBMITTER: &str = "submitter.os@app:app:nick1udwig.os";
+    const SEQUENCER: &str = "rollup.os@sequencer:provider-dao-rollup:nick1udwig.os";
+    const BACKUP_SEQUENCER: &str = "backup.os@sequencer:provider-dao-rollup:nick1udwig.os";
+    const ROUTER_PROCESS: &str = "router:provider_dao_router:nick1udwig.os";
+    const ROUTERS: [&str; 2] = ["router-a.os", "router-b.os"];
+    const IMAGES_DIR: &str = "/comfyui_client:nick1udwig.os/images";
+    const WORKFLOWS_DIR: &str = "/comfyui_client:nick1udwig.os/workflows";
+    const STATE_DIR: &str = "/comfyui_client:nick1udwig.os/state";
+    const PNG: &[u8] = &[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, 1, 2, 3];
+
+    /// What the client has done through its `Transport`, and what it is answered.
+    #[derive(Debug, Default)]
+    struct Mock {
+        /// Answers to `call_sequencer`, in order; once they run out, every
+        /// sequencer is unreachable.
+        sequencer_responses: VecDeque<Result<SequencerResponse, SequencerError>>,
+        sequencer_calls: Vec<(Address, SequencerRequest)>,
+        router_requests: Vec<(Address, PublicRequest, Option<Vec<u8>>)>,
+        requests: Vec<(Address, Vec<u8>)>,
+        responses: Vec<Vec<u8>>,
+        http_responses: Vec<(http::StatusCode, Option<HashMap<String, String>>, Vec<u8>)>,
+        ws_pushes: Vec<(u32, Vec<u8>)>,
+        /// The blob of the message being handled.
+        blob: Option<Vec<u8>>,
+        timers: Vec<(u64, Vec<u8>)>,
+        sleeps: Vec<u64>,
+        saved_states: Vec<Vec<u8>>,
+        files: HashMap<String, Vec<u8>>,
+        /// Directories made by `create_dir`; those holding files exist too.
+        dirs: HashSet<String>,
+        /// Writes to paths under any of these fail.
+        failing_paths: Vec<String>,
+    }
+
+    #[derive(Debug, Clone, Default)]
+    struct MockTransport(Rc<RefCell<Mock>>);
+
+    impl MockTransport {
+        fn check_writable(&self, path: &str) -> anyhow::Result<()> {
+            if self.0.borrow().failing_paths.iter().any(|failing| path.starts_with(failing.as_str())) {
+                return Err(anyhow::anyhow!("disk full writing {path}"));
+            }
+            Ok(())
+        }
+
+        /// Every response made, parsed as `T`.
+        fn responses<T: DeserializeOwned>(&self) -> Vec<T> {
+            self.0.borrow().responses.iter().map(|body| serde_json::from_slice(body).unwrap()).collect()
+        }
+
+        /// Every request sent to `target` other than through `send_to_router`, parsed as `T`.
+        fn requests_to<T: DeserializeOwned>(&self, target: &str) -> Vec<T> {
+            self.0
+                .borrow()
+                .requests
+                .iter()
+                .filter(|(address, _)| address.to_string() == target)
+                .map(|(_, body)| serde_json::from_slice(body).unwrap())
+                .collect()
+        }
+
+        fn timer_kinds(&self) -> Vec<TimerKind> {
+            self.0
+                .borrow()
+                .timers
+                .iter()
+                .map(|(_, context)| serde_json::from_slice::<TimerContext>(context).unwrap().kind)
+                .collect()
+        }
+    }
+
+    impl Transport for MockTransport {
+        fn call_sequencer(
+            &self,
+            sequencer: &Address,
+            request: &SequencerRequest,
+        ) -> Result<SequencerResponse, SequencerError> {
+            let mut mock = self.0.borrow_mut();
+            mock.sequencer_calls.push((sequencer.clone(), request.clone()));
+            let response = mock.sequencer_responses.pop_front().unwrap_or_else(|| {
+                Err(SequencerError::Unreachable { sequencer: sequencer.to_string(), reason: "Timeout".to_string() })
+            });
+            // the answer arrives as a message of its own, whose blob replaces the one being handled
+            mock.blob = response.as_ref().ok().map(|response| serde_json::to_vec(response).unwrap());
+            response
+        }
+
+        fn send_to_router(
+            &self,
+            router: &Address,
+            request: &PublicRequest,
+            blob: Option<Vec<u8>>,
+            _expects_response: Option<(u64, Vec<u8>)>,
+        ) -> anyhow::Result<()> {
+            self.0.borrow_mut().router_requests.push((router.clone(), request.clone(), blob));
+            Ok(())
+        }
+
+        fn send_request(&self, target: &Address, body: Vec<u8>, _blob: Option<Vec<u8>>) -> anyhow::Result<()> {
+            self.0.borrow_mut().requests.push((target.clone(), body));
+            Ok(())
+        }
+
+        fn respond(&self, body: Vec<u8>) -> anyhow::Result<()> {
+            self.0.borrow_mut().responses.push(body);
+            Ok(())
+        }
+
+        fn respond_http(
+            &self,
+            status: http::StatusCode,
+            headers: Option<HashMap<String, String>>,
+            body: Vec<u8>,
+        ) -> anyhow::Result<()> {
+            self.0.borrow_mut().http_responses.push((status, headers, body));
+            Ok(())
+        }
+
+        fn push_ws(&self, channel_id: u32, body: Vec<u8>) -> anyhow::Result<()> {
+            self.0.borrow_mut().ws_pushes.push((channel_id, body));
+            Ok(())
+        }
+
+        fn blob(&self) -> Option<Vec<u8>> {
+            self.0.borrow().blob.clone()
+        }
+
+        fn set_timer(&self, ms: u64, context: Vec<u8>) {
+            self.0.borrow_mut().timers.push((ms, context));
+        }
+
+        fn sleep(&self, ms: u64) -> anyhow::Result<()> {
+            self.0.borrow_mut().sleeps.push(ms);
+            Ok(())
+        }
+
+        fn set_state(&self, bytes: &[u8]) {
+            self.0.borrow_mut().saved_states.push(bytes.to_vec());
+        }
+
+        fn state(&self) -> Option<Vec<u8>> {
+            self.0.borrow().saved_states.last().cloned()
+        }
+
+        fn create_dir(&self, path: &str) -> anyhow::Result<()> {
+            self.check_writable(path)?;
+            self.0.borrow_mut().dirs.insert(path.to_string());
+            Ok(())
+        }
+
+        fn read_dir(&self, path: &str) -> anyhow::Result<Vec<vfs::DirEntry>> {
+            let mock = self.0.borrow();
+            let prefix = format!("{path}/");
+            let mut entries: BTreeMap<String, vfs::FileType> = BTreeMap::new();
+            for file in mock.files.keys().chain(mock.dirs.iter()) {
+                let Some(rest) = file.strip_prefix(&prefix) else {
+                    continue;
+                };
+                let is_file = mock.files.contains_key(file) && !rest.contains('/');
+                let name = rest.split('/').next().unwrap();
+                let file_type = if is_file { vfs::FileType::File } else { vfs::FileType::Directory };
+                entries.insert(format!("{prefix}{name}"), file_type);
+            }
+            if entries.is_empty() && !mock.dirs.contains(path) {
+                return Err(anyhow::anyhow!("no such directory {path}"));
+            }
+            Ok(entries.into_iter().map(|(path, file_type)| vfs::DirEntry { path, file_type }).collect())
+        }
+
+        fn read_file(&self, path: &str) -> anyhow::Result<Vec<u8>> {
+            let files = &self.0.borrow().files;
+            files.get(path).cloned().ok_or_else(|| anyhow::anyhow!("no such file {path}"))
+        }
+
+        fn write_file(&self, path: &str, bytes: &[u8]) -> anyhow::Result<()> {
+            self.check_writable(path)?;
+            self.0.borrow_mut().files.insert(path.to_string(), bytes.to_vec());
+            Ok(())
+        }
+
+        fn append_file(&self, path: &str, bytes: &[u8]) -> anyhow::Result<()> {
+            self.check_writable(path)?;
+            self.0.borrow_mut().files.entry(path.to_string()).or_default().extend_from_slice(bytes);
+            Ok(())
+        }
+
+        fn remove_file(&self, path: &str) -> anyhow::Result<()> {
+            self.0.borrow_mut().files.remove(path);
+            Ok(())
+        }
+
+        fn remove_dir(&self, path: &str) -> anyhow::Result<()> {
+            let mut mock = self.0.borrow_mut();
+            let prefix = format!("{path}/");
+            if mock.files.keys().any(|file| file.starts_with(&prefix)) {
+                return Err(anyhow::anyhow!("directory {path} is not empty"));
+            }
+            mock.dirs.remove(path);
+            Ok(())
+        }
+    }
+
+    fn address(address: &str) -> Address {
+        Address::from_str(address).unwrap()
+    }
+
+    fn router(node: &str) -> Address {
+        Address::new(node, ROUTER_PROCESS.parse::<ProcessId>().unwrap())
+    }
+
+    /// A wallet with private key `n`, which must be nonzero.
+    fn wallet(n: u64) -> LocalWallet {
+        format!("{n:064x}").parse().unwrap()
+    }
+
+    /// `wallet`'s EIP-191 signature over `payload` serialized as JSON.
+    fn sign(wallet: &LocalWallet, payload: &impl Serialize) -> Vec<u8> {
+        let signature = wallet.sign_message_sync(&serde_json::to_vec(payload).unwrap()).unwrap();
+        signature.as_bytes().to_vec()
+    }
+
+    /// The wallet of `ROUTERS[index]`.
+    fn router_wallet(index: usize) -> LocalWallet {
+        wallet(index as u64 + 1)
+    }
+
+    /// A client configured with a router process, `SEQUENCER`, and the
+    /// first `num_routers` of `ROUTERS` as DAO members.
+    fn client(mock: &MockTransport, num_routers: usize) -> State {
+        let mut state = State::default();
+        state.transport = Box::new(mock.clone());
+        state.router_process = Some(ROUTER_PROCESS.parse().unwrap());
+        state.rollup_sequencers = vec![address(SEQUENCER)];
+        for (index, node) in ROUTERS.iter().take(num_routers).enumerate() {
+            state.on_chain_state.routers.push(node.to_string());
+            state.on_chain_state.members.insert(node.to_string(), router_wallet(index).address());
+        }
+        state
+    }
+
+    fn job() -> JobParameters {
+        serde_json::from_value(serde_json::json!({ "workflow": "txt2img", "parameters": "{}" })).unwrap()
+    }
+
+    fn request(source: &Address, body: &impl Serialize) -> Message {
+        Message::Request {
+            source: source.clone(),
+            expects_response: None,
+            body: serde_json::to_vec(body).unwrap(),
+            metadata: None,
+            capabilities: vec![],
+        }
+    }
+
+    fn response(source: &Address, body: &impl Serialize, context: Option<Vec<u8>>) -> Message {
+        Message::Response {
+            source: source.clone(),
+            body: serde_json::to_vec(body).unwrap(),
+            metadata: None,
+            context,
+            capabilities: vec![],
+        }
+    }
+
+    /// Handle `message` as the main loop does.
+    fn handle(state: &mut State, message: &Message) -> anyhow::Result<()> {
+        let result = handle_message(&address(OUR), message, IMAGES_DIR, WORKFLOWS_DIR, state);
+        run_effects(state);
+        result
+    }
+
+    /// Deliver the most recently set timer of a kind `matches` accepts.
+    fn fire_timer(
+        state: &mut State,
+        mock: &MockTransport,
+        matches: impl Fn(&TimerKind) -> bool,
+    ) -> anyhow::Result<()> {
+        let context = mock
+            .0
+            .borrow()
+            .timers
+            .iter()
+            .rev()
+            .map(|(_, context)| context.clone())
+            .find(|context| matches(&serde_json::from_slice::<TimerContext>(context).unwrap().kind))
+            .expect("no such timer set");
+        let timer = address("our.os@timer:distro:sys");
+        handle(state, &response(&timer, &serde_json::Value::Null, Some(context)))
+    }
+
+    fn is_job_timer(kind: &TimerKind, phase: fn(&JobPhase) -> bool) -> bool {
+        matches!(kind, TimerKind::Job(job_timer) if phase(&job_timer.phase))
+    }
+
+    /// Submit `job()` from `SUBMITTER`; it is dispatched if nothing is in flight.
+    fn submit(state: &mut State) {
+        handle(state, &request(&address(SUBMITTER), &PublicRequest::RunJob(job()))).unwrap();
+    }
+
+    /// The router `ROUTERS[index]` answers the attempt sent with `generation`.
+    fn run_response(index: usize, response_to_run: RunResponse, generation: u64) -> Message {
+        let context = serde_json::to_vec(&generation).unwrap();
+        response(&router(ROUTERS[index]), &PublicResponse::RunJob(response_to_run), Some(context))
+    }
+
+    /// The router `ROUTERS[index]` accepts the current attempt at the current job as `job_id`.
+    fn queue(state: &mut State, index: usize, job_id: u64) {
+        let generation = state.current_job.as_ref().map(|current_job| current_job.timer_generation).unwrap_or(0);
+        handle(state, &run_response(index, RunResponse::JobQueued { job_id }, generation)).unwrap();
+    }
+
+    /// A `JobUpdate` carrying all of `image`, signed by `ROUTERS[index]`.
+    fn job_update(index: usize, job_id: u64, is_final: bool, image: &[u8]) -> PublicRequest {
+        let payload = JobUpdateSignedPayload { job_id, image_sha256: hex::encode(Sha256::digest(image)), is_final };
+        PublicRequest::JobUpdate {
+            job_id,
+            is_final,
+            signature: Ok(sign(&router_wallet(index), &payload)),
+            sha256: None,
+            chunk: None,
+        }
+    }
+
+    /// Deliver `update` from `ROUTERS[index]` with `blob`.
+    fn send_update(
+        state: &mut State,
+        mock: &MockTransport,
+        index: usize,
+        update: &PublicRequest,
+        blob: &[u8],
+    ) -> anyhow::Result<()> {
+        mock.0.borrow_mut().blob = Some(blob.to_vec());
+        let result = handle(state, &request(&router(ROUTERS[index]), update));
+        mock.0.borrow_mut().blob = None;
+        result
+    }
+
+    const VOTER: &str = "voter.os";
+
+    fn voter_wallet() -> LocalWallet {
+        wallet(100)
+    }
+
+    /// A client whose only DAO member is `VOTER`.
+    fn voting_client(mock: &MockTransport) -> State {
+        let mut state = client(mock, 2);
+        state.on_chain_state.members = HashMap::from([(VOTER.to_string(), voter_wallet().address())]);
+        state
+    }
+
+    /// A vote by `VOTER` on `proposal`.
+    fn vote(proposal: &Proposal, is_yea: bool) -> SignedVote {
+        let vote = Vote { proposal_hash: hash_proposal(proposal), is_yea };
+        let signature = sign(&voter_wallet(), &vote);
+        SignedVote { vote, signature }
+    }
+
+    /// `proposal`, created at `created_at`, with `VOTER`'s yea vote.
+    fn passing(proposal: Proposal, created_at: u64) -> ProposalInProgress {
+        let votes = HashMap::from([(VOTER.to_string(), vote(&proposal, true))]);
+        ProposalInProgress { proposal, votes, created_at, expires_at: created_at + PROPOSAL_LIFETIME_SECONDS }
+    }
+
+    /// Merge `proposals` as a fetch from the sequencer does, then apply those that passed.
+    fn fetch_proposals(state: &mut State, proposals: &[ProposalInProgress]) {
+        let proposals = proposals
+            .iter()
+            .map(|proposal| (hash_proposal(&proposal.proposal), proposal.clone()))
+            .collect();
+        state.on_chain_state.merge_proposals(proposals);
+        apply_passed_proposals(state);
+    }
+
+    fn dao_state(routers: &[&str]) -> OnChainDaoState {
+        OnChainDaoState {
+            routers: routers.iter().map(|router| router.to_string()).collect(),
+            serve_timeout_seconds: 90,
+            ..OnChainDaoState::default()
+        }
+    }
+
+    #[test]
+    fn await_chain_state_reads_dao_from_sequencer() {
+        let mock = MockTransport::default();
+        let mut state = client(&mock, 0);
+        let read = SequencerResponse::Read(ReadResponse::All(dao_state(&ROUTERS)));
+        mock.0.borrow_mut().sequencer_responses.push_back(Ok(read));
+
+        await_chain_state(&mut state).unwrap();
+        run_effects(&mut state);
+
+        assert_eq!(state.on_chain_state.routers, ROUTERS);
+        assert_eq!(state.on_chain_state.serve_timeout_seconds, 90);
+        assert_eq!(state.active_sequencer, Some(address(SEQUENCER)));
+        assert!(state.last_chain_fetch.is_some());
+        let mock = mock.0.borrow();
+        assert!(matches!(
+            mock.sequencer_calls.as_slice(),
+            [(sequencer, SequencerRequest::Read(ReadRequest::All))] if sequencer.to_string() == SEQUENCER
+        ));
+        assert!(!mock.saved_states.is_empty());
+    }
+
+    #[test]
+    fn await_chain_state_falls_back_to_next_sequencer() {
+        let mock = MockTransport::default();
+        let mut state = client(&mock, 0);
+        state.rollup_sequencers.push(address(BACKUP_SEQUENCER));
+        let unreachable =
+            SequencerError::Unreachable { sequencer: SEQUENCER.to_string(), reason: "Offline".to_string() };
+        let read = SequencerResponse::Read(ReadResponse::All(dao_state(&ROUTERS)));
+        mock.0.borrow_mut().sequencer_responses.extend([Err(unreachable), Ok(read)]);
+
+        await_chain_state(&mut state).unwrap();
+
+        assert_eq!(state.active_sequencer, Some(address(BACKUP_SEQUENCER)));
+        assert_eq!(state.on_chain_state.routers, ROUTERS);
+        assert!(mock.0.borrow().sleeps.is_empty());
+    }
+
+    #[test]
+    fn await_chain_state_gives_up_with_backoff() {
+        let mock = MockTransport::default();
+        let mut state = client(&mock, 1);
+
+        assert!(await_chain_state(&mut state).is_err());
+
+        let mock = mock.0.borrow();
+        assert_eq!(mock.sequencer_calls.len(), SEQUENCER_READ_ATTEMPTS as usize);
+        assert_eq!(mock.sleeps, [500, 1000]);
+        assert_eq!(state.on_chain_state.routers, &ROUTERS[..1]);
+    }
+
+    #[test]
+    fn refreshes_close_together_fetch_once() {
+        let mock = MockTransport::default();
+        let mut state = client(&mock, 0);
+        let read = SequencerResponse::Read(ReadResponse::All(dao_state(&ROUTERS)));
+        mock.0.borrow_mut().sequencer_responses.push_back(Ok(read));
+        arm_chain_refresh_timer(&mut state).unwrap();
+        run_effects(&mut state);
+
+        fire_timer(&mut state, &mock, |kind| matches!(kind, TimerKind::ChainRefresh)).unwrap();
+        handle(&mut state, &request(&address(OUR), &AdminRequest::GetRollupState { force: false })).unwrap();
+
+        assert_eq!(mock.0.borrow().sequencer_calls.len(), 1);
+        let responses: Vec<AdminResponse> = mock.responses();
+        assert!(matches!(
+            responses.as_slice(),
+            [AdminResponse::GetRollupState { fetched_at: Some(_), err: None }]
+        ));
+    }
+
+    #[test]
+    fn sequencer_change_refetches_once() {
+        let mock = MockTransport::default();
+        let mut state = voting_client(&mock);
+        let third_sequencer = "third.os@sequencer:provider-dao-rollup:nick1udwig.os";
+        // each sequencer's DAO has passed a proposal moving to the next
+        for next_sequencer in [BACKUP_SEQUENCER, third_sequencer] {
+            let change = passing(Proposal::ChangeSequencer(next_sequencer.to_string()), 1);
+            let dao = OnChainDaoState {
+                members: state.on_chain_state.members.clone(),
+                proposals: HashMap::from([(hash_proposal(&change.proposal), change)]),
+                ..dao_state(&ROUTERS)
+            };
+            mock.0.borrow_mut().sequencer_responses.push_back(Ok(SequencerResponse::Read(ReadResponse::All(dao))));
+        }
+
+        await_chain_state(&mut state).unwrap();
+
+        let sequencers: Vec<String> =
+            mock.0.borrow().sequencer_calls.iter().map(|(sequencer, _)| sequencer.to_string()).collect();
+        assert_eq!(sequencers, [SEQUENCER, BACKUP_SEQUENCER]);
+        assert_eq!(state.rollup_sequencers[0], address(third_sequencer));
+    }
+
+    #[test]
+    fn corrupt_primary_state_loads_from_backup() {
+        let mut saved = State::default();
+        saved.router_process = Some(ROUTER_PROCESS.parse().unwrap());
+        let backup = serde_json::to_vec(&saved).unwrap();
+
+        let state = State::restore(Some(b"{\"version\": 2, \"current_job\"".to_vec()), || Some(backup.clone()));
+        assert_eq!(state.router_process, saved.router_process);
+
+        let state = State::restore(None, || Some(backup.clone()));
+        assert_eq!(state.router_process, saved.router_process);
+
+        let state = State::restore(Some(serde_json::to_vec(&State::default()).unwrap()), || Some(backup.clone()));
+        assert_eq!(state.router_process, None);
+
+        let state = State::restore(Some(b"corrupt".to_vec()), || Some(b"also corrupt".to_vec()));
+        assert_eq!(state.router_process, None);
+    }
+
+    #[test]
+    fn failed_backup_still_saves_state() {
+        let mock = MockTransport::default();
+        let mut state = client(&mock, 1);
+        state.backup_path = Some(format!("{STATE_DIR}/{STATE_BACKUP_FILE}"));
+        mock.0.borrow_mut().failing_paths.push(STATE_DIR.to_string());
+
+        state.persist().unwrap();
+
+        let mock = mock.0.borrow();
+        assert_eq!(mock.saved_states.len(), 1);
+        assert!(mock.files.is_empty());
+    }
+
+    #[test]
+    fn missing_state_is_loaded_from_backup() {
+        let mock = MockTransport::default();
+        let mut backup = State::default();
+        backup.router_process = Some(ROUTER_PROCESS.parse().unwrap());
+        let backup_path = format!("{STATE_DIR}/{STATE_BACKUP_FILE}");
+        mock.0.borrow_mut().files.insert(backup_path.clone(), serde_json::to_vec(&backup).unwrap());
+
+        let state = State::load(Box::new(mock.clone()), STATE_DIR);
+        assert_eq!(state.router_process, backup.router_process);
+        assert_eq!(state.backup_path, Some(backup_path));
+    }
+
+    #[test]
+    fn job_runs_from_submission_to_final_image() {
+        let mock = MockTransport::default();
+        let mut state = client(&mock, 1);
+
+        submit(&mut state);
+        let responses: Vec<PublicResponse> = mock.responses();
+        assert!(matches!(
+            responses.as_slice(),
+            [PublicResponse::RunJob(RunResponse::QueuePosition { position: 0, .. })]
+        ));
+        assert!(matches!(
+            mock.0.borrow().router_requests.as_slice(),
+            [(target, PublicRequest::RunJob(_), None)] if *target == router(ROUTERS[0])
+        ));
+        assert!(is_job_timer(&mock.timer_kinds()[0], |phase| matches!(phase, JobPhase::Queue)));
+
+        queue(&mut state, 0, 7);
+        assert_eq!(state.current_job.as_ref().unwrap().job_id, Some(7));
+        assert!(mock.timer_kinds().iter().any(|kind| is_job_timer(kind, |phase| matches!(phase, JobPhase::Serve))));
+
+        send_update(&mut state, &mock, 0, &job_update(0, 7, false, PNG), PNG).unwrap();
+        assert_eq!(state.current_job.as_ref().unwrap().next_image_number, 1);
+        send_update(&mut state, &mock, 0, &job_update(0, 7, true, PNG), PNG).unwrap();
+
+        assert!(state.current_job.is_none());
+        let record = state.job_history.back().unwrap();
+        assert_eq!((record.job_id, record.image_count), (Some(7), 2));
+        assert_eq!(record.outcome, JobOutcome::Completed);
+        let files = &mock.0.borrow().files;
+        assert_eq!(files.get(&format!("{IMAGES_DIR}/7/0.png")).unwrap(), PNG);
+        assert_eq!(files.get(&format!("{IMAGES_DIR}/7/final.png")).unwrap(), PNG);
+        assert!(files.contains_key(&format!("{IMAGES_DIR}/7/final.json")));
+        let notifications: Vec<PublicResponse> = mock.requests_to(SUBMITTER);
+        assert!(matches!(
+            notifications.as_slice(),
+            [PublicResponse::RunJob(RunResponse::JobQueued { job_id: 7 })]
+        ));
+    }
+
+    #[test]
+    fn queue_timeout_fails_over_to_next_router() {
+        let mock = MockTransport::default();
+        let mut state = client(&mock, 2);
+        submit(&mut state);
+
+        fire_timer(&mut state, &mock, |kind| is_job_timer(kind, |phase| matches!(phase, JobPhase::Queue))).unwrap();
+
+        let current_job = state.current_job.as_ref().unwrap();
+        assert_eq!(current_job.router, ROUTERS[1]);
+        assert_eq!(current_job.routers_tried, ROUTERS);
+        assert!(state.unhealthy_routers.contains(ROUTERS[0]));
+        let router_requests = &mock.0.borrow().router_requests;
+        assert_eq!(router_requests.len(), 2);
+        assert_eq!(router_requests[1].0, router(ROUTERS[1]));
+    }
+
+    #[test]
+    fn queue_timeout_on_last_router_fails_job() {
+        let mock = MockTransport::default();
+        let mut state = client(&mock, 1);
+        submit(&mut state);
+
+        fire_timer(&mut state, &mock, |kind| is_job_timer(kind, |phase| matches!(phase, JobPhase::Queue))).unwrap();
+
+        assert!(state.current_job.is_none());
+        assert!(matches!(state.job_history.back().unwrap().outcome, JobOutcome::Failed(_)));
+        let notifications: Vec<PublicResponse> = mock.requests_to(SUBMITTER);
+        assert!(matches!(notifications.as_slice(), [PublicResponse::RunJob(RunResponse::Error(_))]));
+    }
+
+    #[test]
+    fn serve_timeout_times_out_job() {
+        let mock = MockTransport::default();
+        let mut state = client(&mock, 1);
+        submit(&mut state);
+        queue(&mut state, 0, 7);
+
+        let timed_out =
+            fire_timer(&mut state, &mock, |kind| is_job_timer(kind, |phase| matches!(phase, JobPhase::Serve)));
+
+        assert!(timed_out.is_err());
+        assert!(state.current_job.is_none());
+        assert_eq!(state.job_history.back().unwrap().outcome, JobOutcome::TimedOut);
+        let notifications: Vec<PublicResponse> = mock.requests_to(SUBMITTER);
+        assert!(matches!(notifications.last(), Some(PublicResponse::RunJob(RunResponse::Error(_)))));
+    }
+
+    #[test]
+    fn passed_proposals_apply_each_variant() {
+        let cases: [(Proposal, fn(&State) -> bool); 11] = [
+            (Proposal::ChangeRootNode("root.os".to_string()), |state| state.on_chain_state.routers == ["root.os"]),
+            (Proposal::ChangeQueueResponseTimeoutSeconds(5), |state| {
+                state.on_chain_state.queue_response_timeout_seconds == 5
+            }),
+            (Proposal::ChangeServeTimeoutSeconds(90), |state| state.on_chain_state.serve_timeout_seconds == 90),
+            (Proposal::ChangeMaxOutstandingPayments(3), |state| state.on_chain_state.max_outstanding_payments == 3),
+            (Proposal::ChangePaymentPeriodHours(12), |state| state.on_chain_state.payment_period_hours == 12),
+            (Proposal::ChangeMaxImageBytes(1024), |state| state.on_chain_state.max_image_bytes == 1024),
+            (Proposal::ChangeSequencer(BACKUP_SEQUENCER.to_string()), |state| {
+                state.rollup_sequencers == [address(BACKUP_SEQUENCER), address(SEQUENCER)]
+            }),
+            (Proposal::Kick(VOTER.to_string()), |state| !state.on_chain_state.members.contains_key(VOTER)),
+            (Proposal::AddRouter("router-c.os".to_string()), |state| {
+                state.on_chain_state.routers == [ROUTERS[0], ROUTERS[1], "router-c.os"]
+            }),
+            (Proposal::RemoveRouter(ROUTERS[0].to_string()), |state| state.on_chain_state.routers == [ROUTERS[1]]),
+            (
+                Proposal::AddMember { node: "new.os".to_string(), address: wallet(101).address().to_string() },
+                |state| state.on_chain_state.members.get("new.os") == Some(&wallet(101).address()),
+            ),
+        ];
+        for (proposal, is_applied) in cases {
+            let mock = MockTransport::default();
+            let mut state = voting_client(&mock);
+            let description = format!("{proposal:?}");
+            let proposal_hash = hash_proposal(&proposal);
+
+            fetch_proposals(&mut state, &[passing(proposal, 1)]);
+
+            assert!(is_applied(&state), "{description} was not applied");
+            assert!(state.on_chain_state.proposals.is_empty(), "{description} was not dropped");
+            assert!(state.applied_proposals.contains(&proposal_hash));
+        }
+    }
+
+    #[test]
+    fn refetched_proposals_are_not_applied_again() {
+        let mock = MockTransport::default();
+        let mut state = voting_client(&mock);
+        let add = passing(Proposal::AddRouter("router-c.os".to_string()), 1);
+        let remove = passing(Proposal::RemoveRouter("router-c.os".to_string()), 2);
+
+        fetch_proposals(&mut state, &[add.clone()]);
+        assert_eq!(state.on_chain_state.routers.len(), 3);
+        // the sequencer keeps passed proposals, so every fetch returns them
+        fetch_proposals(&mut state, &[add.clone(), remove.clone()]);
+        fetch_proposals(&mut state, &[add, remove]);
+
+        assert_eq!(state.on_chain_state.routers, ROUTERS);
+        assert_eq!(state.applied_proposals.len(), 2);
+        assert!(state.on_chain_state.proposals.is_empty());
+    }
+
+    #[test]
+    fn proposals_passing_together_apply_oldest_first() {
+        for created_at in [(1, 2), (2, 1)] {
+            let mock = MockTransport::default();
+            let mut state = voting_client(&mock);
+            let thirty = passing(Proposal::ChangeServeTimeoutSeconds(30), created_at.0);
+            let sixty = passing(Proposal::ChangeServeTimeoutSeconds(60), created_at.1);
+
+            fetch_proposals(&mut state, &[thirty, sixty]);
+
+            let newest = if created_at.0 > created_at.1 { 30 } else { 60 };
+            assert_eq!(state.on_chain_state.serve_timeout_seconds, newest);
+        }
+    }
+
+    #[test]
+    fn admin_requests_get_exactly_one_response() {
+        let mock = MockTransport::default();
+        let mut state = client(&mock, 0);
+        let mut admin = |source: &str, body: &AdminRequest| -> AdminResponse {
+            mock.0.borrow_mut().responses.clear();
+            handle(&mut state, &request(&address(source), body)).unwrap();
+            let mut responses: Vec<AdminResponse> = mock.responses();
+            assert_eq!(responses.len(), 1, "{body:?} from {source}");
+            responses.remove(0)
+        };
+
+        // no workflow has been saved, so there is no directory to list
+        assert!(matches!(admin(OUR, &AdminRequest::ListWorkflows), AdminResponse::Error(_)));
+        let save = AdminRequest::SaveWorkflow { name: "txt2img".to_string(), json: "{}".to_string() };
+        assert!(matches!(admin(OUR, &save), AdminResponse::SaveWorkflow { err: None }));
+        let listed = admin(OUR, &AdminRequest::ListWorkflows);
+        assert!(matches!(listed, AdminResponse::ListWorkflows(names) if names == ["txt2img"]));
+        let rejected = admin(SUBMITTER, &AdminRequest::ClearQueue);
+        assert!(matches!(rejected, AdminResponse::Error(_)));
+    }
+
+    /// `job()` taking `images`, named by their position, as `input_images`,
+    /// and the blob carrying them.
+    fn job_with_images(images: &[&[u8]]) -> (JobParameters, Vec<u8>) {
+        let mut job_parameters = job();
+        job_parameters.input_images = images
+            .iter()
+            .enumerate()
+            .map(|(index, image)| InputImage { name: format!("{index}.png"), size: image.len() as u64, sha256: None })
+            .collect();
+        (job_parameters, images.concat())
+    }
+
+    #[test]
+    fn input_image_sizes_that_overflow_are_rejected() {
+        let (mut job_parameters, blob) = job_with_images(&[PNG, PNG]);
+        // wraps around to the blob's length if summed unchecked
+        job_parameters.input_images[0].size = u64::MAX;
+        job_parameters.input_images[1].size = blob.len() as u64 + 1;
+        let result = split_input_images(&mut job_parameters, Some(blob.as_slice()), u64::MAX);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn failed_staging_removes_images_already_staged() {
+        let mock = MockTransport::default();
+        let mut state = client(&mock, 1);
+        let inputs_dir = "/comfyui_client:nick1udwig.os/inputs";
+        state.inputs_dir = Some(inputs_dir.to_string());
+        let second = [PNG, &[4][..]].concat();
+        let second_sha256 = hex::encode(Sha256::digest(&second));
+        mock.0.borrow_mut().failing_paths.push(format!("{inputs_dir}/{second_sha256}"));
+
+        let (job_parameters, blob) = job_with_images(&[PNG, &second]);
+        let input_bytes = Some(blob.as_slice());
+        let result = submit_job(&mut state, job_parameters, input_bytes, address(SUBMITTER), WORKFLOWS_DIR).unwrap();
+
+        assert!(matches!(result, Err(RunResponse::Error(_))));
+        assert!(state.job_queue.is_empty() && state.current_job.is_none());
+        assert!(!mock.0.borrow().files.keys().any(|path| path.starts_with(inputs_dir)));
+    }
+
+    /// A `SendError` of kind `kind` for a ping of `ROUTERS[index]`.
+    fn ping_error(index: usize, kind: SendErrorKind) -> SendError {
+        let context = serde_json::to_vec(&RouterPing { router: ROUTERS[index].to_string() }).unwrap();
+        SendError {
+            kind,
+            target: router(ROUTERS[index]),
+            message: request(&address(OUR), &PublicRequest::Ping),
+            lazy_load_blob: None,
+            context: Some(context),
+        }
+    }
+
+    #[test]
+    fn router_health_follows_whether_pings_arrive() {
+        let mock = MockTransport::default();
+        let mut state = client(&mock, 2);
+        ping_routers(&mut state).unwrap();
+        run_effects(&mut state);
+        assert_eq!(mock.0.borrow().router_requests.len(), 2);
+
+        // routers answer no `Ping`: an error response, or none, still shows they are up
+        let context = serde_json::to_vec(&RouterPing { router: ROUTERS[0].to_string() }).unwrap();
+        let error = PublicResponse::Error("unknown request".to_string());
+        handle(&mut state, &response(&router(ROUTERS[0]), &error, Some(context))).unwrap();
+        handle_send_error(&ping_error(1, SendErrorKind::Timeout), &mut state).unwrap();
+        assert!(state.unhealthy_routers.is_empty());
+        assert!(state.router_health.values().all(|health| health.last_seen.is_some()));
+
+        handle_send_error(&ping_error(1, SendErrorKind::Offline), &mut state).unwrap();
+        assert_eq!(state.unhealthy_routers, HashSet::from([ROUTERS[1].to_string()]));
+    }
+
+    #[test]
+    fn image_gc_ages_jobs_missing_from_history_by_their_sidecars() {
+        let mock = MockTransport::default();
+        let mut state = client(&mock, 0);
+        state.retention_policy = RetentionPolicy { max_jobs_retained: None, max_age_hours: Some(1), dry_run: false };
+        let save_image = |job_id: u64, written_at: Option<u64>| {
+            let mut mock = mock.0.borrow_mut();
+            mock.files.insert(format!("{IMAGES_DIR}/{job_id}/0.png"), PNG.to_vec());
+            if let Some(written_at) = written_at {
+                let metadata = ImageMetadata { job_id, image_number: 0, is_final: true, written_at, parameters: job() };
+                mock.files.insert(format!("{IMAGES_DIR}/{job_id}/0.json"), serde_json::to_vec(&metadata).unwrap());
+            }
+        };
+        save_image(1, Some(now()));
+        save_image(2, Some(now() - 2 * 3600));
+        save_image(3, None);
+
+        collect_garbage(IMAGES_DIR, &state).unwrap();
+
+        let mut kept: Vec<String> = mock.0.borrow().files.keys().cloned().collect();
+        kept.sort();
+        let expected = ["1/0.json", "1/0.png", "3/0.png"].map(|file| format!("{IMAGES_DIR}/{file}"));
+        assert_eq!(kept, expected);
+    }
+
+    #[test]
+    fn unknown_router_is_looked_up_once_per_job() {
+        let mock = MockTransport::default();
+        let mut state = client(&mock, 1);
+        state.on_chain_state.members.clear();
+        submit(&mut state);
+        queue(&mut state, 0, 7);
+
+        // not from the serving router: nothing is looked up
+        let update = job_update(0, 7, false, PNG);
+        assert!(send_update(&mut state, &mock, 1, &update, PNG).is_err());
+        assert!(mock.0.borrow().sequencer_calls.is_empty());
+
+        let member = Some((ROUTERS[0].to_string(), router_wallet(0).address()));
+        let read = SequencerResponse::Read(ReadResponse::Member(member));
+        mock.0.borrow_mut().sequencer_responses.push_back(Ok(read));
+        send_update(&mut state, &mock, 0, &update, PNG).unwrap();
+        assert_eq!(mock.0.borrow().sequencer_calls.len(), 1);
+        assert_eq!(mock.0.borrow().files.get(&format!("{IMAGES_DIR}/7/0.png")).unwrap(), PNG);
+
+        // once looked up, the router is not looked up again for this job
+        state.on_chain_state.members.clear();
+        assert!(send_update(&mut state, &mock, 0, &update, PNG).is_err());
+        assert_eq!(mock.0.borrow().sequencer_calls.len(), 1);
+    }
+
+    #[test]
+    fn signed_vote_verifies_only_its_signer() {
+        // the well-known address of private key 1
+        let signer: AlloyAddress = "0x7E5F4552091A69125d5DfCb7b8C2659029395Bdf".parse().unwrap();
+        assert_eq!(wallet(1).address(), signer);
+        let vote = Vote { proposal_hash: hash_proposal(&Proposal::ChangeServeTimeoutSeconds(90)), is_yea: true };
+        let signed_vote = SignedVote { signature: sign(&wallet(1), &vote), vote };
+        assert!(signed_vote.verify(&signer));
+        assert!(!signed_vote.verify(&wallet(2).address()));
+
+        let mut tampered = signed_vote.clone();
+        tampered.signature[10] ^= 1;
+        assert!(!tampered.verify(&signer));
+        let mut tampered = signed_vote;
+        tampered.vote.is_yea = false;
+        assert!(!tampered.verify(&signer));
+    }
+
+    #[test]
+    fn proposal_hash_is_deterministic() {
+        let add_member = || Proposal::AddMember {
+            node: VOTER.to_string(),
+            address: "0x7E5F4552091A69125d5DfCb7b8C2659029395Bdf".to_string(),
+        };
+        // keccak256 of the JSON, as other nodes compute it: first 8 bytes, big-endian
+        assert_eq!(hash_proposal(&Proposal::ChangeServeTimeoutSeconds(90)), 913389685698246782);
+        assert_eq!(hash_proposal(&add_member()), 12877941568791299918);
+
+        let round_tripped: Proposal = serde_json::from_slice(&serde_json::to_vec(&add_member()).unwrap()).unwrap();
+        assert_eq!(hash_proposal(&round_tripped), hash_proposal(&add_member()));
+        assert_ne!(
+            hash_proposal(&Proposal::ChangeServeTimeoutSeconds(90)),
+            hash_proposal(&Proposal::ChangeServeTimeoutSeconds(91)),
+        );
+    }
+
+    #[test]
+    fn superseded_job_timer_is_ignored() {
+        let mock = MockTransport::default();
+        let mut state = client(&mock, 2);
+        submit(&mut state);
+        // the router answering in time supersedes the queue timer with a serve timer
+        queue(&mut state, 0, 7);
+
+        fire_timer(&mut state, &mock, |kind| is_job_timer(kind, |phase| matches!(phase, JobPhase::Queue))).unwrap();
+
+        let current_job = state.current_job.as_ref().unwrap();
+        assert_eq!((current_job.job_id, current_job.router.as_str()), (Some(7), ROUTERS[0]));
+        assert!(state.unhealthy_routers.is_empty());
+        assert_eq!(mock.0.borrow().router_requests.len(), 1);
+    }
+
+    #[test]
+    fn select_router_goes_round_robin_past_unhealthy_routers() {
+        let mock = MockTransport::default();
+        let mut state = client(&mock, 0);
+        state.on_chain_state.routers = ["a.os", "b.os", "c.os"].map(String::from).to_vec();
+        let select = |state: &mut State| select_router(state).unwrap().node().to_string();
+
+        assert_eq!([select(&mut state), select(&mut state), select(&mut state)], ["a.os", "b.os", "c.os"]);
+        state.unhealthy_routers.insert("a.os".to_string());
+        assert_eq!([select(&mut state), select(&mut state), select(&mut state)], ["b.os", "c.os", "b.os"]);
+        // with none healthy, the rotation goes on regardless
+        state.unhealthy_routers.extend(["b.os", "c.os"].map(String::from));
+        assert_eq!([select(&mut state), select(&mut state)], ["c.os", "a.os"]);
+    }
+
+    #[test]
+    fn malformed_sequencer_addresses_are_rejected() {
+        let malformed = [
+            "0x7E5F4552091A69125d5DfCb7b8C2659029395Bdf",
+            "sequencer:provider-dao-rollup:nick1udwig.os",
+            "@sequencer:provider-dao-rollup:nick1udwig.os",
+            "rollup.os@sequencer:provider-dao-rollup",
+            "",
+        ];
+        for address in malformed {
+            assert!(parse_sequencer_address(address).is_err(), "{address:?}");
+        }
+        assert_eq!(parse_sequencer_address(&format!(" {SEQUENCER} ")).unwrap(), address(SEQUENCER));
+
+        let mock = MockTransport::default();
+        let mut state = client(&mock, 0);
+        let set = AdminRequest::SetRollupSequencer { address: malformed[3].to_string() };
+        handle(&mut state, &request(&address(OUR), &set)).unwrap();
+        let responses: Vec<AdminResponse> = mock.responses();
+        assert!(matches!(responses.as_slice(), [AdminResponse::SetRollupSequencer { err: Some(_) }]));
+        assert_eq!(state.rollup_sequencers, [address(SEQUENCER)]);
+    }
+
+    #[test]
+    fn out_of_order_chunks_are_rejected() {
+        let mock = MockTransport::default();
+        let mut state = client(&mock, 1);
+        submit(&mut state);
+        queue(&mut state, 0, 7);
+        let (head, tail) = PNG.split_at(9);
+        let mut send_chunk = |chunk_index: u32, bytes: &[u8]| {
+            let mut update = job_update(0, 7, false, PNG);
+            if let PublicRequest::JobUpdate { ref mut chunk, .. } = update {
+                *chunk = Some(ImageChunk { image_number: 0, chunk_index, is_last_chunk: chunk_index == 1 });
+            }
+            send_update(&mut state, &mock, 0, &update, bytes)
+        };
+        let is_out_of_order = |result: anyhow::Result<()>| {
+            matches!(result.unwrap_err().downcast_ref::<JobError>(), Some(JobError::ChunkOutOfOrder { .. }))
+        };
+
+        assert!(is_out_of_order(send_chunk(1, tail)));
+        send_chunk(0, head).unwrap();
+        assert!(is_out_of_order(send_chunk(2, tail)));
+        send_chunk(1, tail).unwrap();
+
+        assert_eq!(state.current_job.as_ref().unwrap().next_image_number, 1);
+        assert_eq!(mock.0.borrow().files.get(&format!("{IMAGES_DIR}/7/0.png")).unwrap(), PNG);
+    }
+
+    #[test]
+    fn jitter_is_determined_by_its_entropy() {
+        assert_eq!(jittered(10_000, 0, 12345), 10_000);
+        assert_eq!(jittered(10_000, 10, 12345), jittered(10_000, 10, 12345));
+        // 12345 % 2001 = 339, so 1000 below the interval, plus 339
+        assert_eq!(jittered(10_000, 10, 12345), 9_339);
+        for entropy in [0, 1, 2000, u64::MAX] {
+            assert!((9_000..=11_000).contains(&jittered(10_000, 10, entropy)));
+        }
+        assert_eq!(jittered(10_000, 255, 0), 1);
+        for entropy in [0, u64::MAX] {
+            assert!(jittered(u64::MAX, 10, entropy) >= u64::MAX - u64::MAX / 10);
+        }
+    }
+
+    #[test]
+    fn huge_timer_intervals_saturate() {
+        let mock = MockTransport::default();
+        let mut state = client(&mock, 1);
+        state.chain_refresh_interval_seconds = u64::MAX;
+        state.chain_refresh_jitter_percent = 0;
+        state.router_health_interval_seconds = u64::MAX;
+        arm_chain_refresh_timer(&mut state).unwrap();
+        arm_router_health_timer(&mut state).unwrap();
+        run_effects(&mut state);
+
+        let intervals: Vec<u64> = mock.0.borrow().timers.iter().map(|(ms, _)| *ms).collect();
+        assert_eq!(intervals, [u64::MAX, u64::MAX]);
+    }
+
+    #[test]
+    fn job_seed_is_kept_or_filled_in() {
+        let mock = MockTransport::default();
+        let mut state = client(&mock, 1);
+        let mut seeded = job();
+        seeded.seed = Some(42);
+        submit_job(&mut state, seeded, None, address(SUBMITTER), WORKFLOWS_DIR).unwrap().unwrap();
+        assert_eq!(state.current_job.as_ref().unwrap().parameters.seed, Some(42));
+
+        assert_eq!(generate_seed("entropy"), generate_seed("entropy"));
+        assert_ne!(generate_seed("entropy"), generate_seed("other entropy"));
+        state.current_job = None;
+        submit_job(&mut state, job(), None, address(SUBMITTER), WORKFLOWS_DIR).unwrap().unwrap();
+        assert!(state.current_job.as_ref().unwrap().parameters.seed.is_some());
+    }
+
+    #[test]
+    fn blacklisted_client_is_refused() {
+        let mock = MockTransport::default();
+        let mut state = client(&mock, 1);
+        state.on_chain_state.client_blacklist = vec![address(SUBMITTER).node().to_string()];
+
+        submit(&mut state);
+        let validate = PublicRequest::ValidateJob(job());
+        handle(&mut state, &request(&address(SUBMITTER), &validate)).unwrap();
+
+        let responses: Vec<PublicResponse> = mock.responses();
+        assert!(matches!(
+            responses.as_slice(),
+            [PublicResponse::RunJob(RunResponse::Error(_)), PublicResponse::Validation { ok: false, .. }]
+        ));
+        assert!(state.current_job.is_none() && state.job_queue.is_empty());
+        assert!(mock.0.borrow().router_requests.is_empty());
+    }
+
+    #[test]
+    fn record_vote_keeps_a_members_first_vote() {
+        let proposal = Proposal::ChangeServeTimeoutSeconds(90);
+        let mut in_progress = passing(proposal.clone(), 1);
+        in_progress.votes.clear();
+        let members = HashMap::from([(VOTER.to_string(), voter_wallet().address())]);
+
+        in_progress.record_vote(VOTER, vote(&proposal, true), &members).unwrap();
+        assert!(in_progress.votes[VOTER].vote.is_yea);
+        // re-casting the same vote is a no-op; changing it is refused
+        in_progress.record_vote(VOTER, vote(&proposal, true), &members).unwrap();
+        assert!(in_progress.record_vote(VOTER, vote(&proposal, false), &members).is_err());
+        assert!(in_progress.votes[VOTER].vote.is_yea);
+
+        let other_proposal = Proposal::ChangeServeTimeoutSeconds(91);
+        assert!(in_progress.record_vote(VOTER, vote(&other_proposal, true), &members).is_err());
+        assert!(in_progress.record_vote("stranger.os", vote(&proposal, true), &members).is_err());
+        let impostor = HashMap::from([(VOTER.to_string(), wallet(1).address())]);
+        assert!(in_progress.record_vote(VOTER, vote(&proposal, true), &impostor).is_err());
+        assert_eq!(in_progress.votes.len(), 1);
+    }
+
+    #[test]
+    fn identical_jobs_from_one_node_are_collapsed() {
+        let mock = MockTransport::default();
+        let mut state = client(&mock, 1);
+        let submit_from = |state: &mut State, submitter: &str, parameters: &str| {
+            let mut job_parameters = job();
+            job_parameters.parameters = parameters.to_string();
+            submit_job(state, job_parameters, None, address(submitter), WORKFLOWS_DIR).unwrap()
+        };
+        let other_submitter = "other.os@app:app:nick1udwig.os";
+
+        assert!(matches!(submit_from(&mut state, SUBMITTER, "{}"), Ok(0)));
+        assert!(matches!(submit_from(&mut state, SUBMITTER, "{}"), Ok(0)));
+        queue(&mut state, 0, 7);
+        assert!(matches!(submit_from(&mut state, SUBMITTER, "{}"), Err(RunResponse::JobQueued { job_id: 7 })));
+
+        // queued duplicates collapse onto their position; other nodes' jobs do not
+        assert!(matches!(submit_from(&mut state, SUBMITTER, r#"{"steps": 20}"#), Ok(1)));
+        assert!(matches!(submit_from(&mut state, SUBMITTER, r#"{"steps": 20}"#), Ok(1)));
+        assert!(matches!(submit_from(&mut state, other_submitter, r#"{"steps": 20}"#), Ok(2)));
+        assert_eq!(state.job_queue.len(), 2);
+        assert_eq!(mock.0.borrow().router_requests.len(), 1);
+    }
+
+    /// The state `mock` last saved, loaded as on a restart, and its jobs resumed.
+    fn restart(mock: &MockTransport) -> State {
+        let mut state = State::load(Box::new(mock.clone()), STATE_DIR);
+        mock.0.borrow_mut().timers.clear();
+        resume_jobs(&mut state).unwrap();
+        run_effects(&mut state);
+        state
+    }
+
+    #[test]
+    fn active_job_resumes_after_restart() {
+        let mock = MockTransport::default();
+        let mut state = client(&mock, 1);
+        submit(&mut state);
+
+        // not yet accepted: waits on the router again
+        let restarted = restart(&mock);
+        assert!(restarted.current_job.as_ref().unwrap().job_id.is_none());
+        let kinds = mock.timer_kinds();
+        assert!(matches!(kinds.as_slice(), [kind] if is_job_timer(kind, |phase| matches!(phase, JobPhase::Queue))));
+
+        queue(&mut state, 0, 7);
+        send_update(&mut state, &mock, 0, &job_update(0, 7, false, PNG), PNG).unwrap();
+        let mut state = restart(&mock);
+        let current_job = state.current_job.as_ref().unwrap();
+        assert_eq!((current_job.job_id, current_job.next_image_number), (Some(7), 1));
+        let kinds = mock.timer_kinds();
+        assert!(matches!(kinds.as_slice(), [kind] if is_job_timer(kind, |phase| matches!(phase, JobPhase::Serve))));
+
+        send_update(&mut state, &mock, 0, &job_update(0, 7, true, PNG), PNG).unwrap();
+        assert!(state.current_job.is_none());
+        assert_eq!(state.job_history.back().unwrap().image_count, 2);
+    }
+
+    #[test]
+    fn malformed_router_process_is_rejected() {
+        let mock = MockTransport::default();
+        let mut state = client(&mock, 0);
+        let other_process = "router:other_router:nick1udwig.os";
+        for process_id in ["not-a-process", other_process] {
+            let set = AdminRequest::SetRouterProcess { process_id: process_id.to_string() };
+            handle(&mut state, &request(&address(OUR), &set)).unwrap();
+        }
+
+        let responses: Vec<AdminResponse> = mock.responses();
+        assert!(matches!(
+            responses.as_slice(),
+            [AdminResponse::SetRouterProcess { err: Some(_) }, AdminResponse::SetRouterProcess { err: None }]
+        ));
+        assert_eq!(state.router_process, Some(other_process.parse().unwrap()));
+    }
+
+    #[test]
+    fn recycled_job_id_cannot_touch_a_new_job() {
+        let mock = MockTransport::default();
+        let mut state = client(&mock, 1);
+        submit(&mut state);
+        queue(&mut state, 0, 7);
+        let current_job = state.current_job.as_ref().unwrap();
+        let generation = current_job.timer_generation;
+        let timer = |job_id: Option<u64>, generation: u64| JobTimer { job_id, phase: JobPhase::Serve, generation };
+        assert!(timer(Some(7), generation).is_current(current_job));
+        assert!(timer(None, generation).is_current(current_job));
+        assert!(!timer(Some(8), generation).is_current(current_job));
+        assert!(!timer(Some(7), generation - 1).is_current(current_job));
+
+        send_update(&mut state, &mock, 0, &job_update(0, 7, true, PNG), PNG).unwrap();
+        let mut next_job = job();
+        next_job.parameters = r#"{"steps": 20}"#.to_string();
+        submit_job(&mut state, next_job, None, address(SUBMITTER), WORKFLOWS_DIR).unwrap().unwrap();
+        // a late, or repeated, JobQueued for the finished job
+        queue(&mut state, 0, 7);
+        assert_eq!(state.current_job.as_ref().unwrap().job_id, None);
+    }
+
+    #[test]
+    fn no_routers_refuses_jobs() {
+        let mock = MockTransport::default();
+        let mut state = client(&mock, 0);
+        assert!(select_router(&mut state).is_none());
+
+        submit(&mut state);
+
+        let responses: Vec<PublicResponse> = mock.responses();
+        assert!(matches!(
+            responses.as_slice(),
+            [PublicResponse::RunJob(RunResponse::Error(reason))] if reason == "no routers configured"
+        ));
+        assert!(state.current_job.is_none() && state.job_queue.is_empty());
+    }
+
+    #[test]
+    fn failed_image_write_keeps_job_current() {
+        let mock = MockTransport::default();
+        let mut state = client(&mock, 1);
+        submit(&mut state);
+        queue(&mut state, 0, 7);
+        mock.0.borrow_mut().failing_paths.push(format!("{IMAGES_DIR}/7"));
+
+        let update = job_update(0, 7, false, PNG);
+        let error = send_update(&mut state, &mock, 0, &update, PNG).unwrap_err();
+        assert!(matches!(error.downcast_ref::<JobError>(), Some(JobError::WriteFailed { job_id: 7, .. })));
+        let current_job = state.current_job.as_ref().unwrap();
+        assert_eq!((current_job.job_id, current_job.next_image_number), (Some(7), 0));
+
+        // the router resends the image under the same number
+        mock.0.borrow_mut().failing_paths.clear();
+        send_update(&mut state, &mock, 0, &update, PNG).unwrap();
+        assert_eq!(state.current_job.as_ref().unwrap().next_image_number, 1);
+        assert_eq!(mock.0.borrow().files.get(&format!("{IMAGES_DIR}/7/0.png")).unwrap(), PNG);
+    }
+
+    #[test]
+    fn timers_dispatch_by_kind_and_epoch() {
+        let mock = MockTransport::default();
+        let mut state = client(&mock, 1);
+        let deliver = |state: &mut State, context: Vec<u8>| {
+            let timer = address("our.os@timer:distro:sys");
+            handle(state, &response(&timer, &serde_json::Value::Null, Some(context))).unwrap();
+        };
+        let timer = |kind: TimerKind, epoch: u64| serde_json::to_vec(&TimerContext { kind, epoch }).unwrap();
+        state.timer_epoch = 1;
+
+        state.on_chain_state.payment_period_hours = 1;
+        state.outstanding_payments = 3;
+        state.payment_period_started_at = 0;
+        deliver(&mut state, timer(TimerKind::PaymentReset, 1));
+        assert_eq!(state.outstanding_payments, 0);
+
+        deliver(&mut state, timer(TimerKind::ChainRefresh, 1));
+        let sequencer_calls = mock.0.borrow().sequencer_calls.len();
+        assert!(sequencer_calls > 0);
+
+        deliver(&mut state, timer(TimerKind::RouterHealth, 1));
+        assert!(matches!(mock.0.borrow().router_requests.as_slice(), [(_, PublicRequest::Ping, None)]));
+
+        state.retention_policy.max_jobs_retained = Some(0);
+        let metadata = ImageMetadata { job_id: 3, image_number: 0, is_final: true, written_at: 1, parameters: job() };
+        mock.0.borrow_mut().files.insert(format!("{IMAGES_DIR}/3/0.json"), serde_json::to_vec(&metadata).unwrap());
+        deliver(&mut state, timer(TimerKind::ImageGc, 1));
+        assert!(!mock.0.borrow().files.keys().any(|path| path.starts_with(IMAGES_DIR)));
+
+        submit(&mut state);
+        fire_timer(&mut state, &mock, |kind| is_job_timer(kind, |phase| matches!(phase, JobPhase::Queue))).unwrap();
+        assert!(state.current_job.is_none());
+        assert!(matches!(state.job_history.back().unwrap().outcome, JobOutcome::Failed(_)));
+
+        // neither a timer from before the last restart nor one of unknown shape does anything
+        deliver(&mut state, timer(TimerKind::ChainRefresh, 0));
+        deliver(&mut state, b"{\"kind\":\"Retired\",\"epoch\":1}".to_vec());
+        assert_eq!(mock.0.borrow().sequencer_calls.len(), sequencer_calls);
+    }
+
+    #[test]
+    fn answers_to_an_abandoned_attempt_are_ignored() {
+        let mock = MockTransport::default();
+        let mut state = client(&mock, 2);
+        submit(&mut state);
+        let first_attempt = state.current_job.as_ref().unwrap().timer_generation;
+        fire_timer(&mut state, &mock, |kind| is_job_timer(kind, |phase| matches!(phase, JobPhase::Queue))).unwrap();
+        assert_eq!(state.current_job.as_ref().unwrap().router, ROUTERS[1]);
+
+        // the router failed over from answers late; so does the new one, to the old attempt
+        handle(&mut state, &run_response(0, RunResponse::JobQueued { job_id: 7 }, first_attempt)).unwrap();
+        handle(&mut state, &run_response(0, RunResponse::Error("busy".to_string()), first_attempt)).unwrap();
+        handle(&mut state, &run_response(1, RunResponse::PaymentRequired, first_attempt)).unwrap();
+        let current_job = state.current_job.as_ref().unwrap();
+        assert_eq!((current_job.job_id, current_job.router.as_str()), (None, ROUTERS[1]));
+        assert_eq!(state.outstanding_payments, 0);
+
+        queue(&mut state, 1, 8);
+        assert_eq!(state.current_job.as_ref().unwrap().job_id, Some(8));
+    }
+
+    /// `http_server` delivering a request of `method` to `path`, bound with `url_params`.
+    fn http_request(method: &str, path: &str, url_params: serde_json::Value) -> Message {
+        let bound_path = format!("/{}{path}", address(OUR).process);
+        let body = serde_json::json!({ "Http": {
+            "source_socket_addr": null,
+            "method": method,
+            "url": format!("http://localhost:8080{bound_path}"),
+            "bound_path": bound_path,
+            "headers": {},
+            "url_params": url_params,
+            "query_params": {},
+        }});
+        request(&address("our.os@http_server:distro:sys"), &body)
+    }
+
+    #[test]
+    fn http_jobs_and_images_go_through_transport() {
+        let mock = MockTransport::default();
+        let mut state = client(&mock, 1);
+        mock.0.borrow_mut().blob = Some(serde_json::to_vec(&job()).unwrap());
+        handle(&mut state, &http_request("POST", JOB_HTTP_PATH, serde_json::json!({}))).unwrap();
+        mock.0.borrow_mut().blob = None;
+        assert_eq!(mock.0.borrow().router_requests.len(), 1);
+
+        queue(&mut state, 0, 7);
+        send_update(&mut state, &mock, 0, &job_update(0, 7, true, PNG), PNG).unwrap();
+        let saved = serde_json::json!({ "job_id": "7", "file": "0.png" });
+        handle(&mut state, &http_request("GET", IMAGES_HTTP_PATH, saved)).unwrap();
+        let missing = serde_json::json!({ "job_id": "7", "file": "1.png" });
+        handle(&mut state, &http_request("GET", IMAGES_HTTP_PATH, missing)).unwrap();
+
+        let mock = mock.0.borrow();
+        let statuses: Vec<http::StatusCode> = mock.http_responses.iter().map(|(status, _, _)| *status).collect();
+        assert_eq!(statuses, [http::StatusCode::ACCEPTED, http::StatusCode::OK, http::StatusCode::NOT_FOUND]);
+        assert_eq!(mock.http_responses[0].2, br#"{"queue_position":0}"#);
+        assert_eq!(mock.http_responses[1].2, PNG);
+    }
+
+    #[test]
+    fn saved_images_are_pushed_to_open_websockets() {
+        let mock = MockTransport::default();
+        let mut state = client(&mock, 1);
+        let open = serde_json::json!({ "WebSocketOpen": { "path": UPDATES_WS_PATH, "channel_id": 3 } });
+        handle(&mut state, &request(&address("our.os@http_server:distro:sys"), &open)).unwrap();
+
+        submit(&mut state);
+        queue(&mut state, 0, 7);
+        send_update(&mut state, &mock, 0, &job_update(0, 7, true, PNG), PNG).unwrap();
+
+        let pushes = mock.0.borrow().ws_pushes.clone();
+        assert!(matches!(pushes.as_slice(), [(3, _)]));
+        let event: ImageEvent = serde_json::from_slice(&pushes[0].1).unwrap();
+        assert_eq!((event.job_id, event.image_number, event.is_final), (7, 0, true));
+    }
+
+    #[test]
+    fn saved_workflows_are_read_through_transport() {
+        let mock = MockTransport::default();
+        let mut state = client(&mock, 1);
+        mock.0.borrow_mut().files.insert(format!("{WORKFLOWS_DIR}/portrait.json"), b"portrait-v2".to_vec());
+        let mut missing = job();
+        missing.workflow = "@landscape".to_string();
+        let mut saved = job();
+        saved.workflow = "@portrait".to_string();
+
+        handle(&mut state, &request(&address(SUBMITTER), &PublicRequest::ValidateJob(missing))).unwrap();
+        handle(&mut state, &request(&address(SUBMITTER), &PublicRequest::RunJob(saved))).unwrap();
+
+        let responses: Vec<PublicResponse> = mock.responses();
+        assert!(matches!(
+            responses.as_slice(),
+            [PublicResponse::Validation { ok: false, .. }, PublicResponse::RunJob(RunResponse::QueuePosition { .. })]
+        ));
+        let mock = mock.0.borrow();
+        let [(_, PublicRequest::RunJob(sent), _)] = mock.router_requests.as_slice() else {
+            panic!("expected one RunJob, got {:?}", mock.router_requests);
         };
+        assert_eq!((sent.workflow.as_str(), sent.name.as_deref()), ("portrait-v2", Some("portrait")));
     }
 }